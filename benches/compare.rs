@@ -200,13 +200,150 @@ fn bench_concurrent(c: &mut Criterion) {
     group.finish();
 }
 
+/* ---------------- Concurrent Read Benchmark ---------------- */
+
+fn bench_concurrent_reads(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concurrent_reads");
+
+    let threads = 8;
+    let ops = 50_000;
+    let n = 100_000;
+
+    let carbon = setup_carbonmap(n);
+    let dash = setup_dashmap(n);
+    let rw = setup_rwlock(n);
+
+    group.bench_function("carbonmap", |b| {
+        b.iter(|| {
+            let mut handles = vec![];
+
+            for t in 0..threads {
+                let m = carbon.clone();
+
+                handles.push(thread::spawn(move || {
+                    for i in 0..ops {
+                        if i % 10 == 0 {
+                            m.insert(((t * ops + i) % n) as u64, i as u64);
+                        } else {
+                            let _ = m.get(&(((t * ops + i) % n) as u64));
+                        }
+                    }
+                }));
+            }
+
+            for h in handles {
+                h.join().unwrap();
+            }
+        });
+    });
+
+    group.bench_function("dashmap", |b| {
+        b.iter(|| {
+            let mut handles = vec![];
+
+            for t in 0..threads {
+                let m = dash.clone();
+
+                handles.push(thread::spawn(move || {
+                    for i in 0..ops {
+                        if i % 10 == 0 {
+                            m.insert(((t * ops + i) % n) as u64, i as u64);
+                        } else {
+                            let _ = m.get(&(((t * ops + i) % n) as u64)).map(|v| *v);
+                        }
+                    }
+                }));
+            }
+
+            for h in handles {
+                h.join().unwrap();
+            }
+        });
+    });
+
+    group.bench_function("rwlock", |b| {
+        b.iter(|| {
+            let mut handles = vec![];
+
+            for t in 0..threads {
+                let m = rw.clone();
+
+                handles.push(thread::spawn(move || {
+                    for i in 0..ops {
+                        if i % 10 == 0 {
+                            m.write().insert(((t * ops + i) % n) as u64, i as u64);
+                        } else {
+                            let _ = m.read().get(&(((t * ops + i) % n) as u64)).copied();
+                        }
+                    }
+                }));
+            }
+
+            for h in handles {
+                h.join().unwrap();
+            }
+        });
+    });
+
+    group.finish();
+}
+
+/* ---------------- Entry Benchmark ---------------- */
+
+fn bench_entry(c: &mut Criterion) {
+    let mut group = c.benchmark_group("entry");
+
+    let keys = 1_000u64;
+
+    group.bench_function("carbonmap", |b| {
+        let map = CarbonMap::new();
+
+        b.iter(|| {
+            for i in 0..10_000 {
+                let _ = map.entry(i % keys)
+                    .and_modify(|v| *v += 1)
+                    .or_insert(1u64);
+            }
+        });
+    });
+
+    group.bench_function("dashmap", |b| {
+        let map = DashMap::new();
+
+        b.iter(|| {
+            for i in 0..10_000 {
+                let _ = map.entry(i % keys)
+                    .and_modify(|v| *v += 1)
+                    .or_insert(1u64);
+            }
+        });
+    });
+
+    group.bench_function("rwlock", |b| {
+        let map = RwLock::new(HashMap::new());
+
+        b.iter(|| {
+            for i in 0..10_000 {
+                let mut m = map.write();
+                m.entry(i % keys)
+                    .and_modify(|v| *v += 1)
+                    .or_insert(1u64);
+            }
+        });
+    });
+
+    group.finish();
+}
+
 /* ---------------- Register ---------------- */
 
 criterion_group!(
     benches,
     bench_reads,
     bench_writes,
-    bench_concurrent
+    bench_concurrent,
+    bench_concurrent_reads,
+    bench_entry
 );
 
 criterion_main!(benches);
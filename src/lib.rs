@@ -4,31 +4,163 @@
 //!
 //! ⚠️ Early alpha.
 
-use std::collections::HashMap;
-use std::hash::Hash;
+use std::borrow::Borrow;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::hash_map::{self, DefaultHasher, RandomState};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::ops::{AddAssign, Deref, DerefMut, SubAssign};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, OnceLock};
+use std::time::{Duration, Instant};
 
-use parking_lot::{RwLock, RwLockWriteGuard, MappedRwLockWriteGuard};
+use parking_lot::{Condvar, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard, MappedRwLockWriteGuard};
 
 /// Concurrent hash map
-pub struct CarbonMap<K, V> {
-    inner: RwLock<HashMap<K, V>>,
+///
+/// Entries are spread over lock-striped shards picked by key hash, so
+/// operations on keys in different shards do not contend. Methods that touch
+/// a single key lock only that key's shard; methods that span the whole map
+/// lock every shard in index order, so anything documented as running under
+/// one lock still sees and applies a consistent state. The exceptions visit
+/// shards one at a time and say so: `len`, `is_empty`, `retain`,
+/// `retain_nonempty`, `clear`, `compact` and `rehash_seed`.
+///
+/// # Deadlocks
+///
+/// The shard locks are not reentrant. While an [`Entry`] or a guard returned
+/// from it is alive, the calling thread holds a shard's write lock, and
+/// calling any other method on the same map from that thread may deadlock.
+/// Drop the entry first, or use [`CarbonMap::try_entry`] to fail instead of
+/// blocking.
+pub struct CarbonMap<K, V, S = RandomState> {
+    shards: Box<[Shard<K, V, S>]>,
+    hasher: S,
+    // `64 - log2(shards.len())`; see `shard_index`.
+    shift: u32,
+    inflight: Mutex<HashSet<K>>,
+    inflight_done: Condvar,
+    fair: bool,
 }
 
 /* ================= Entry Types ================= */
 
-pub enum Entry<'a, K, V> {
-    Occupied(OccupiedEntry<'a, K, V>),
-    Vacant(VacantEntry<'a, K, V>),
+/// View into a single entry, holding its shard's write lock
+///
+/// See the deadlock note on [`CarbonMap`].
+pub enum Entry<'a, K, V, S = RandomState> {
+    Occupied(OccupiedEntry<'a, K, V, S>),
+    Vacant(VacantEntry<'a, K, V, S>),
 }
 
-pub struct OccupiedEntry<'a, K, V> {
-    key: K,
-    guard: RwLockWriteGuard<'a, HashMap<K, V>>,
+// `inner` borrows the map behind `guard`, see `make_entry`. It is declared
+// first so it is dropped before the lock is released.
+pub struct OccupiedEntry<'a, K, V, S = RandomState> {
+    inner: hash_map::OccupiedEntry<'a, K, V>,
+    guard: ShardWriteGuard<'a, K, V, S>,
 }
 
-pub struct VacantEntry<'a, K, V> {
-    key: K,
-    guard: RwLockWriteGuard<'a, HashMap<K, V>>,
+pub struct VacantEntry<'a, K, V, S = RandomState> {
+    inner: hash_map::VacantEntry<'a, K, V>,
+    guard: ShardWriteGuard<'a, K, V, S>,
+}
+
+/// Like [`Entry`], but looked up by a borrowed key
+///
+/// The key is only converted to an owned `K` when inserting into a vacant slot.
+pub enum EntryRef<'a, 'q, K, Q: ?Sized, V, S = RandomState> {
+    Occupied(OccupiedEntryRef<'a, 'q, K, Q, V, S>),
+    Vacant(VacantEntryRef<'a, 'q, K, Q, V, S>),
+}
+
+pub struct OccupiedEntryRef<'a, 'q, K, Q: ?Sized, V, S = RandomState> {
+    key: &'q Q,
+    guard: ShardWriteGuard<'a, K, V, S>,
+    // Points into `guard`'s map, found by the single lookup in `entry_ref()`.
+    // The write lock is held and the table is never resized while the
+    // entry is alive, so the slot stays put.
+    val: *mut V,
+}
+
+pub struct VacantEntryRef<'a, 'q, K, Q: ?Sized, V, S = RandomState> {
+    key: &'q Q,
+    guard: ShardWriteGuard<'a, K, V, S>,
+}
+
+/* ================= Snapshot Types ================= */
+
+/// Result of `export_and_reset`
+#[derive(Debug, Clone)]
+pub struct ExportSnapshot<K, V> {
+    /// Per-key values before the reset
+    pub values: Vec<(K, V)>,
+    /// Sum of all values
+    pub sum: f64,
+    /// Mean of all values, `0.0` if empty
+    pub mean: f64,
+}
+
+/// Operation counts returned by `reconcile`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReconcileStats {
+    /// Keys that were absent and got inserted
+    pub inserted: usize,
+    /// Keys whose value changed
+    pub updated: usize,
+    /// Keys not in the desired state that were removed
+    pub deleted: usize,
+}
+
+/// `(to_insert, to_update, to_delete)` returned by `reconcile_plan`
+pub type ReconcilePlan<K, V> = (Vec<(K, V)>, Vec<(K, V)>, Vec<K>);
+
+/// Read-only view of the whole map while every shard is locked
+///
+/// Passed to `get_or_derive` in place of the `&HashMap` it took before the
+/// map was sharded.
+pub struct MapView<'a, K, V, S = RandomState> {
+    shards: &'a WriteShards<'a, K, V, S>,
+}
+
+impl<K, V, S> MapView<'_, K, V, S>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher + Clone,
+{
+    /// Borrowed value
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.shards.get(key)
+    }
+
+    /// Whether `key` is present
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.shards.contains_key(key)
+    }
+
+    /// Number of entries
+    pub fn len(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Whether the map has no entries
+    pub fn is_empty(&self) -> bool {
+        self.shards.is_empty()
+    }
+
+    /// All entries, in unspecified order
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.shards.iter()
+    }
+
+    /// All keys, in unspecified order
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.shards.keys()
+    }
+
+    /// All values, in unspecified order
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.shards.values()
+    }
 }
 
 /* ================= Impl ================= */
@@ -38,16 +170,136 @@ where
     K: Eq + Hash + Clone,
 {
     /// New map
+    ///
+    /// Shard slots are allocated up front, at least 128 bytes each. The
+    /// default is one shard per available core, capped at 16, so an empty
+    /// map costs up to about 2 KiB. Prefer `with_shard_count(1)` for large
+    /// numbers of small, rarely contended maps.
     pub fn new() -> Self {
+        Self::with_fair_locking(false)
+    }
+
+    /// New map with room for at least `cap` entries
+    ///
+    /// The capacity is split evenly across shards.
+    pub fn with_capacity(cap: usize) -> Self {
+        Self::build(RandomState::new(), default_shard_count(), cap, false)
+    }
+
+    /// New map, optionally releasing locks fairly in `insert`/`get`/`remove`
+    ///
+    /// Fair unlocking hands the lock directly to the next waiter, so writers
+    /// are not starved by a steady stream of readers. This costs throughput
+    /// under contention.
+    pub fn with_fair_locking(fair: bool) -> Self {
+        Self::build(RandomState::new(), default_shard_count(), 0, fair)
+    }
+
+    /// New map split into `shards` shards, rounded up to a power of two
+    ///
+    /// The default is one shard per available core, capped at 16. A single
+    /// shard behaves like one global lock.
+    pub fn with_shard_count(shards: usize) -> Self {
+        Self::build(RandomState::new(), shards, 0, false)
+    }
+
+    /// Build from parallel key and value slices
+    ///
+    /// # Panics
+    ///
+    /// Panics if the slices differ in length.
+    pub fn from_columns(keys: &[K], vals: &[V]) -> Self
+    where
+        V: Clone,
+    {
+        assert_eq!(keys.len(), vals.len(), "from_columns: length mismatch");
+
+        let map = keys.iter().cloned().zip(vals.iter().cloned()).collect();
+
+        Self::from_map(map, false)
+    }
+
+    /// Rebuild each shard's table with a freshly seeded hasher
+    ///
+    /// Limits the window for hash-flooding attacks on long-lived maps. Shards
+    /// are rebuilt one at a time. The hasher that picks a key's shard keeps
+    /// its seed, so entries stay put.
+    pub fn rehash_seed(&self) {
+        for shard in self.shards.iter() {
+            let mut map = shard.map.write();
+
+            let mut fresh = HashMap::with_capacity_and_hasher(map.len(), RandomState::new());
+            fresh.extend(map.drain());
+
+            *map = fresh;
+        }
+    }
+}
+
+impl<K, V, S> CarbonMap<K, V, S>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher + Clone,
+{
+    /// New map using `hasher` to hash keys
+    pub fn with_hasher(hasher: S) -> Self {
+        Self::build(hasher, default_shard_count(), 0, false)
+    }
+
+    /// New map using `hasher`, split into `shards` shards
+    ///
+    /// See [`CarbonMap::with_shard_count`].
+    pub fn with_hasher_and_shard_count(hasher: S, shards: usize) -> Self {
+        Self::build(hasher, shards, 0, false)
+    }
+
+    fn build(hasher: S, shards: usize, cap: usize, fair: bool) -> Self {
+        let n = shards.max(1).next_power_of_two();
+        let per_shard = cap.div_ceil(n);
+
         Self {
-            inner: RwLock::new(HashMap::new()),
+            shards: (0..n)
+                .map(|_| Shard {
+                    map: RwLock::new(HashMap::with_capacity_and_hasher(per_shard, hasher.clone())),
+                    readers: AtomicUsize::new(0),
+                })
+                .collect(),
+            hasher,
+            shift: 64 - n.trailing_zeros(),
+            inflight: Mutex::new(HashSet::new()),
+            inflight_done: Condvar::new(),
+            fair,
         }
     }
 
-    /// Insert or overwrite
-    pub fn insert(&self, key: K, val: V) {
-        let mut map = self.inner.write();
-        map.insert(key, val);
+    fn from_map(map: HashMap<K, V, S>, fair: bool) -> Self {
+        let mut this = Self::build(map.hasher().clone(), default_shard_count(), map.len(), fair);
+        this.extend(map);
+        this
+    }
+
+    /// Number of shards
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    fn shard_index<Q: Hash + ?Sized>(&self, key: &Q) -> usize {
+        // Take the bits just below the top 7, which hashbrown uses for its
+        // control bytes, so the shard choice does not skew each shard's table.
+        let hash = self.hasher.hash_one(key) << 7;
+        hash.checked_shr(self.shift).unwrap_or(0) as usize
+    }
+
+    fn shard<Q: Hash + ?Sized>(&self, key: &Q) -> &RwLock<HashMap<K, V, S>> {
+        &self.shards[self.shard_index(key)].map
+    }
+
+    /// Insert or overwrite, returning the previous value
+    pub fn insert(&self, key: K, val: V) -> Option<V> {
+        let mut map = self.shard(&key).write();
+        let old = map.insert(key, val);
+        self.unlock_write(map);
+        old
     }
 
     /// Get cloned value
@@ -55,219 +307,4161 @@ where
     where
         V: Clone,
     {
-        let map = self.inner.read();
+        let map = self.read_shard(key);
         map.get(key).cloned()
     }
 
+    /// Run `f` on a borrowed value under the read lock
+    pub fn get_ref<F, R>(&self, key: &K, f: F) -> Option<R>
+    where
+        F: FnOnce(&V) -> R,
+    {
+        let map = self.read_shard(key);
+        map.get(key).map(f)
+    }
+
+    /// Whether `key` is present
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.read_shard(key).contains_key(key)
+    }
+
+    /// Mutate the value in place if present
+    pub fn update<F, R>(&self, key: &K, f: F) -> Option<R>
+    where
+        F: FnOnce(&mut V) -> R,
+    {
+        let mut map = self.shard(key).write();
+        map.get_mut(key).map(f)
+    }
+
     /// Remove key
     pub fn remove(&self, key: &K) -> Option<V> {
-        let mut map = self.inner.write();
-        map.remove(key)
+        let mut map = self.shard(key).write();
+        let val = map.remove(key);
+        self.unlock_write(map);
+        val
     }
 
-    /// Entry API
-    pub fn entry(&self, key: K) -> Entry<'_, K, V> {
-        let guard = self.inner.write();
+    /// Remove the key only if `pred` holds for its value
+    pub fn remove_if<F>(&self, key: &K, pred: F) -> Option<V>
+    where
+        F: FnOnce(&V) -> bool,
+    {
+        let mut map = self.shard(key).write();
 
-        if guard.contains_key(&key) {
-            Entry::Occupied(OccupiedEntry { key, guard })
+        if pred(map.get(key)?) {
+            map.remove(key)
         } else {
-            Entry::Vacant(VacantEntry { key, guard })
+            None
         }
     }
-}
 
-/* ================= Entry Impl ================= */
+    /// Number of entries
+    ///
+    /// Shards are counted one at a time, so if other threads are mutating
+    /// the map concurrently the total may be stale by the time it is used.
+    pub fn len(&self) -> usize {
+        (0..self.shards.len()).map(|i| self.read_lock(i).len()).sum()
+    }
 
-impl<'a, K, V> Entry<'a, K, V>
-where
-    K: Eq + Hash + Clone,
-{
-    pub fn or_insert(self, default: V) -> MappedRwLockWriteGuard<'a, V> {
-        match self {
-            Entry::Occupied(e) => e.into_guard(),
-            Entry::Vacant(e) => e.insert(default),
-        }
+    /// Whether the map has no entries
+    ///
+    /// Shards are checked one at a time, like `len`.
+    pub fn is_empty(&self) -> bool {
+        (0..self.shards.len()).all(|i| self.read_lock(i).is_empty())
     }
 
-    pub fn or_insert_with<F>(self, f: F) -> MappedRwLockWriteGuard<'a, V>
+    /// Keep only entries for which `f` returns true
+    ///
+    /// Shards are visited one at a time, each under its own write lock.
+    pub fn retain<F>(&self, mut f: F)
     where
-        F: FnOnce() -> V,
+        F: FnMut(&K, &mut V) -> bool,
     {
-        match self {
-            Entry::Occupied(e) => e.into_guard(),
-            Entry::Vacant(e) => e.insert(f()),
+        for shard in self.shards.iter() {
+            shard.map.write().retain(&mut f);
         }
     }
 
-    pub fn and_modify<F>(self, f: F) -> Self
-    where
-        F: FnOnce(&mut V),
-    {
-        match self {
-            Entry::Occupied(mut e) => {
-                f(e.get_mut());
-                Entry::Occupied(e)
-            }
-            e => e,
+    /// Remove all entries, keeping the allocated tables
+    ///
+    /// Shards are cleared one at a time, so concurrent inserts into an
+    /// already-cleared shard survive. Use `clear_counted` to clear atomically.
+    pub fn clear(&self) {
+        for shard in self.shards.iter() {
+            shard.map.write().clear();
         }
     }
-}
 
-impl<'a, K, V> OccupiedEntry<'a, K, V>
-where
-    K: Eq + Hash + Clone,
-{
-    fn into_guard(self) -> MappedRwLockWriteGuard<'a, V> {
-        RwLockWriteGuard::map(self.guard, |m| {
-            m.get_mut(&self.key).unwrap()
-        })
+    /// Remove all entries and release the tables' memory under one write lock
+    pub fn clear_and_shrink(&self) {
+        let mut map = self.write_all();
+
+        for shard in &mut map.guards {
+            shard.clear();
+            shard.shrink_to_fit();
+        }
     }
 
-    pub fn get(&self) -> &V {
-        self.guard.get(&self.key).unwrap()
+    /// Approximate number of shard read locks currently held
+    ///
+    /// Only reads taken through this map's own methods are counted, and the
+    /// value may be stale by the time it is observed.
+    pub fn reader_count(&self) -> usize {
+        self.shards.iter().map(|s| s.readers.load(Ordering::Relaxed)).sum()
     }
 
-    pub fn get_mut(&mut self) -> &mut V {
-        self.guard.get_mut(&self.key).unwrap()
+    fn read_lock(&self, shard: usize) -> ReadGuard<'_, K, V, S> {
+        let shard = &self.shards[shard];
+        let guard = shard.map.read();
+        shard.readers.fetch_add(1, Ordering::Relaxed);
+
+        ReadGuard {
+            guard: Some(guard),
+            readers: &shard.readers,
+            fair: self.fair,
+        }
     }
-}
 
-impl<'a, K, V> VacantEntry<'a, K, V>
-where
-    K: Eq + Hash + Clone,
-{
-    pub fn insert(mut self, val: V) -> MappedRwLockWriteGuard<'a, V> {
-        self.guard.insert(self.key.clone(), val);
+    fn read_shard(&self, key: &K) -> ReadGuard<'_, K, V, S> {
+        self.read_lock(self.shard_index(key))
+    }
 
-        RwLockWriteGuard::map(self.guard, |m| {
-            m.get_mut(&self.key).unwrap()
-        })
+    fn read_all(&self) -> ReadShards<'_, K, V, S> {
+        Shards {
+            map: self,
+            guards: (0..self.shards.len()).map(|i| self.read_lock(i)).collect(),
+        }
     }
-}
 
-/* ================= Tests ================= */
+    /// Read-lock the distinct shards holding `keys`, in ascending index order
+    fn read_keys<'k, I>(&self, keys: I) -> KeyShards<'_, K, V, S>
+    where
+        I: IntoIterator<Item = &'k K>,
+        K: 'k,
+    {
+        let mut idx: Vec<usize> = keys.into_iter().map(|k| self.shard_index(k)).collect();
+        idx.sort_unstable();
+        idx.dedup();
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::sync::Arc;
-    use std::thread;
+        KeyShards {
+            map: self,
+            guards: idx.into_iter().map(|i| (i, self.read_lock(i))).collect(),
+        }
+    }
 
-    #[test]
-    fn basic_insert_get() {
-        let map = CarbonMap::new();
+    fn write_all(&self) -> WriteShards<'_, K, V, S> {
+        Shards {
+            map: self,
+            guards: self.shards.iter().map(|s| s.map.write()).collect(),
+        }
+    }
 
-        map.insert("a", 1);
-        map.insert("b", 2);
+    fn unlock_write(&self, guard: ShardWriteGuard<'_, K, V, S>) {
+        if self.fair {
+            RwLockWriteGuard::unlock_fair(guard);
+        }
+    }
 
-        assert_eq!(map.get(&"a"), Some(1));
-        assert_eq!(map.get(&"b"), Some(2));
-        assert_eq!(map.get(&"c"), None);
+    /// Entry API
+    ///
+    /// Always takes the shard's write lock; see the deadlock note on [`CarbonMap`].
+    pub fn entry(&self, key: K) -> Entry<'_, K, V, S> {
+        let guard = self.shard(&key).write();
+        Self::make_entry(key, guard)
     }
 
-    #[test]
-    fn overwrite() {
-        let map = CarbonMap::new();
+    /// Entry API by borrowed key
+    ///
+    /// Avoids building an owned key unless a vacant entry is inserted into.
+    pub fn entry_ref<'q, Q>(&self, key: &'q Q) -> EntryRef<'_, 'q, K, Q, V, S>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        let mut guard = self.shard(key).write();
 
-        map.insert("x", 10);
-        map.insert("x", 20);
+        match guard.get_mut(key).map(|v| v as *mut V) {
+            Some(val) => EntryRef::Occupied(OccupiedEntryRef { key, guard, val }),
+            None => EntryRef::Vacant(VacantEntryRef { key, guard }),
+        }
+    }
 
-        assert_eq!(map.get(&"x"), Some(20));
+    /// Entry API without blocking
+    ///
+    /// Returns `None` if the shard's lock is currently held, including by an
+    /// entry alive on this thread.
+    pub fn try_entry(&self, key: K) -> Option<Entry<'_, K, V, S>> {
+        let guard = self.shard(&key).try_write()?;
+        Some(Self::make_entry(key, guard))
     }
 
-    #[test]
-    fn remove_basic() {
-        let map = CarbonMap::new();
+    fn make_entry(key: K, mut guard: ShardWriteGuard<'_, K, V, S>) -> Entry<'_, K, V, S> {
+        // SAFETY: the map lives inside the shard's lock, not in the guard, so
+        // it stays put when the guard moves and is exclusively ours until the
+        // guard is dropped. The entry types drop `inner` before `guard`.
+        let map = unsafe { &mut *(&mut *guard as *mut HashMap<K, V, S>) };
 
-        map.insert("a", 1);
+        match map.entry(key) {
+            hash_map::Entry::Occupied(inner) => Entry::Occupied(OccupiedEntry { inner, guard }),
+            hash_map::Entry::Vacant(inner) => Entry::Vacant(VacantEntry { inner, guard }),
+        }
+    }
 
-        let v = map.remove(&"a");
+    /// Remove up to `max` entries
+    ///
+    /// Selection order is unspecified.
+    pub fn take_batch(&self, max: usize) -> Vec<(K, V)> {
+        let mut map = self.write_all();
 
-        assert_eq!(v, Some(1));
-        assert_eq!(map.get(&"a"), None);
+        let keys: Vec<K> = map.keys().take(max).cloned().collect();
+
+        keys.into_iter()
+            .filter_map(|k| map.remove_entry(&k))
+            .collect()
     }
 
-    #[test]
-    fn entry_or_insert() {
-        let map = CarbonMap::new();
+    /// Insert `placeholder` only if `key` is absent
+    ///
+    /// Returns whether the claim succeeded.
+    pub fn try_claim(&self, key: K, placeholder: V) -> bool {
+        let mut map = self.shard(&key).write();
 
-        let v = map.entry("a").or_insert(10);
+        if map.contains_key(&key) {
+            return false;
+        }
 
-        assert_eq!(*v, 10);
+        map.insert(key, placeholder);
+        true
     }
 
-    #[test]
-    fn entry_and_modify() {
-        let map = CarbonMap::new();
+    /// Apply a fallible update, restoring the old value on error
+    ///
+    /// Returns `Ok(false)` if the key is missing.
+    pub fn try_update<F, E>(&self, key: &K, f: F) -> Result<bool, E>
+    where
+        V: Clone,
+        F: FnOnce(&mut V) -> Result<(), E>,
+    {
+        let mut map = self.shard(key).write();
 
-        let _ = map.entry("a").or_insert(1);
+        let Some(val) = map.get_mut(key) else {
+            return Ok(false);
+        };
 
-        let _ = map.entry("a")
-            .and_modify(|v| *v += 5)
-            .or_insert(0);
+        let old = val.clone();
 
-        assert_eq!(map.get(&"a"), Some(6));
-    }
+        if let Err(e) = f(val) {
+            *val = old;
+            return Err(e);
+        }
 
-    #[test]
-    fn entry_or_insert_with() {
-        let map = CarbonMap::new();
+        Ok(true)
+    }
 
-        let v = map.entry("k").or_insert_with(|| 42);
+    /// Apply per-key closures under one write lock
+    ///
+    /// Keys that are not present are skipped.
+    pub fn apply_updates<F>(&self, updates: HashMap<K, F>)
+    where
+        F: FnOnce(&mut V),
+    {
+        let mut map = self.write_all();
 
-        assert_eq!(*v, 42);
+        for (key, f) in updates {
+            if let Some(val) = map.get_mut(&key) {
+                f(val);
+            }
+        }
     }
 
-    #[test]
-    fn concurrent_inserts() {
-        let map = Arc::new(CarbonMap::new());
+    /// Remove all entries, returning how many were removed
+    pub fn clear_counted(&self) -> usize {
+        let mut map = self.write_all();
 
-        let mut handles = vec![];
+        let n = map.len();
+        map.clear();
+        n
+    }
 
-        for i in 0..8 {
-            let m = map.clone();
+    /// Value at percentile `p` (0.0–1.0), or `None` if empty
+    ///
+    /// `p` is clamped to the valid range; the nearest rank is used.
+    pub fn percentile(&self, p: f64) -> Option<V>
+    where
+        V: Copy + Ord,
+    {
+        let map = self.read_all();
 
-            handles.push(thread::spawn(move || {
-                for j in 0..1000 {
-                    let k = format!("{}-{}", i, j);
-                    m.insert(k, j);
-                }
-            }));
-        }
+        let mut vals: Vec<V> = map.values().copied().collect();
+        drop(map);
 
-        for h in handles {
-            h.join().unwrap();
+        if vals.is_empty() {
+            return None;
         }
 
-        assert!(map.get(&"0-0".to_string()).is_some());
-        assert!(map.get(&"7-999".to_string()).is_some());
+        vals.sort_unstable();
+
+        let p = p.clamp(0.0, 1.0);
+        let idx = (p * (vals.len() - 1) as f64).round() as usize;
+
+        Some(vals[idx])
     }
 
-    #[test]
-    fn concurrent_entry_updates() {
-        let map = Arc::new(CarbonMap::new());
+    /// Move entries matching `pred` into `dest`, returning the count moved
+    ///
+    /// Both maps are locked in address order. Migrating into `self` is a no-op.
+    pub fn migrate_where<F>(&self, dest: &CarbonMap<K, V, S>, mut pred: F) -> usize
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        if std::ptr::eq(self, dest) {
+            return 0;
+        }
 
-        let mut handles = vec![];
+        let (mut src, mut dst) = write_pair(self, dest);
 
-        for _ in 0..10 {
-            let m = map.clone();
+        let keys: Vec<K> = src
+            .iter()
+            .filter(|(k, v)| pred(k, v))
+            .map(|(k, _)| k.clone())
+            .collect();
 
-            handles.push(thread::spawn(move || {
-                for _ in 0..1000 {
-                    let _ = m.entry("counter")
-                        .and_modify(|v| *v += 1)
-                        .or_insert(1);
-                }
-            }));
+        for k in &keys {
+            if let Some((k, v)) = src.remove_entry(k) {
+                dst.insert(k, v);
+            }
         }
 
-        for h in handles {
-            h.join().unwrap();
+        keys.len()
+    }
+
+    /// Shrink each shard's table to fit its current entries
+    ///
+    /// Shards are visited one at a time, each under its own write lock.
+    pub fn compact(&self) {
+        for shard in self.shards.iter() {
+            shard.map.write().shrink_to_fit();
         }
+    }
 
-        let val = map.get(&"counter");
+    /// Value of the first present key in `keys`
+    ///
+    /// Only the shards holding `keys` are locked, together.
+    pub fn get_first(&self, keys: &[K]) -> Option<V>
+    where
+        V: Clone,
+    {
+        let map = self.read_keys(keys);
+        keys.iter().find_map(|k| map.get(k)).cloned()
+    }
 
-        assert_eq!(val, Some(10000));
+    /// Stored value for each request, or its default if absent
+    ///
+    /// Only the shards holding the requested keys are locked, together.
+    pub fn get_or_defaults(&self, requests: &[(K, V)]) -> Vec<V>
+    where
+        V: Clone,
+    {
+        let map = self.read_keys(requests.iter().map(|(k, _)| k));
+
+        requests
+            .iter()
+            .map(|(k, d)| map.get(k).unwrap_or(d).clone())
+            .collect()
+    }
+
+    /// Export all values with their sum and mean, resetting each to `Default`
+    pub fn export_and_reset(&self) -> ExportSnapshot<K, V>
+    where
+        K: Clone,
+        V: Default + Copy + Into<f64>,
+    {
+        let mut map = self.write_all();
+
+        let mut values = Vec::with_capacity(map.len());
+        let mut sum = 0.0;
+
+        for (k, v) in map.iter_mut() {
+            let old = std::mem::take(v);
+            sum += old.into();
+            values.push((k.clone(), old));
+        }
+
+        let mean = if values.is_empty() {
+            0.0
+        } else {
+            sum / values.len() as f64
+        };
+
+        ExportSnapshot { values, sum, mean }
+    }
+
+    /// Whether any key is present in both maps
+    pub fn intersects(&self, other: &CarbonMap<K, V, S>) -> bool {
+        if std::ptr::eq(self, other) {
+            return !self.is_empty();
+        }
+
+        let (a, b) = read_pair(self, other);
+
+        let (small, large) = if a.len() <= b.len() { (&a, &b) } else { (&b, &a) };
+
+        small.guards.iter().any(|g| g.keys().any(|k| large.contains_key(k)))
+    }
+
+    /// Keys present in exactly one of the two maps
+    pub fn key_symmetric_difference(&self, other: &CarbonMap<K, V, S>) -> Vec<K> {
+        if std::ptr::eq(self, other) {
+            return Vec::new();
+        }
+
+        let (a, b) = read_pair(self, other);
+
+        a.keys()
+            .filter(|k| !b.contains_key(k))
+            .chain(b.keys().filter(|k| !a.contains_key(k)))
+            .cloned()
+            .collect()
+    }
+
+    /// Insert, then evict arbitrary entries until the total size is at most `max_bytes`
+    ///
+    /// Other entries are evicted before the new one; if the new entry alone
+    /// exceeds the budget it is evicted too.
+    pub fn insert_within_budget<F>(&self, key: K, val: V, max_bytes: usize, size_of: F)
+    where
+        F: Fn(&K, &V) -> usize,
+    {
+        let mut map = self.write_all();
+
+        map.insert(key.clone(), val);
+
+        let mut total: usize = map.iter().map(|(k, v)| size_of(k, v)).sum();
+
+        if total <= max_bytes {
+            return;
+        }
+
+        let mut victims: Vec<K> = map.keys().filter(|k| **k != key).cloned().collect();
+        victims.push(key);
+
+        for k in victims {
+            if total <= max_bytes {
+                break;
+            }
+
+            if let Some(v) = map.remove(&k) {
+                total -= size_of(&k, &v);
+            }
+        }
+    }
+
+    /// Remove and return the value only if `pred` holds
+    pub fn take_if<F>(&self, key: &K, pred: F) -> Option<V>
+    where
+        F: FnOnce(&V) -> bool,
+    {
+        self.remove_if(key, pred)
+    }
+
+    /// Per-slot key counts when hashing into `buckets` slots
+    ///
+    /// Uses the map's own hasher. Returns an empty Vec for zero buckets.
+    pub fn bucket_distribution(&self, buckets: usize) -> Vec<usize> {
+        let mut counts = vec![0; buckets];
+
+        if buckets == 0 {
+            return counts;
+        }
+
+        let map = self.read_all();
+
+        for k in map.keys() {
+            let h = self.hasher.hash_one(k);
+            counts[(h % buckets as u64) as usize] += 1;
+        }
+
+        counts
+    }
+
+    /// Remove the key only if its value equals `expected`
+    pub fn compare_and_remove(&self, key: &K, expected: &V) -> bool
+    where
+        V: PartialEq,
+    {
+        let mut map = self.shard(key).write();
+
+        if map.get(key) == Some(expected) {
+            map.remove(key);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Get the value, or compute it once across threads
+    ///
+    /// If another thread is already computing `key`, waits up to `timeout`
+    /// for its result and returns `None` on timeout instead of computing a
+    /// duplicate.
+    pub fn get_or_compute_timeout<F>(&self, key: K, timeout: Duration, f: F) -> Option<V>
+    where
+        V: Clone,
+        F: FnOnce() -> V,
+    {
+        if let Some(v) = self.get(&key) {
+            return Some(v);
+        }
+
+        let deadline = Instant::now() + timeout;
+        let mut inflight = self.inflight.lock();
+
+        loop {
+            if let Some(v) = self.get(&key) {
+                return Some(v);
+            }
+
+            if !inflight.contains(&key) {
+                break;
+            }
+
+            if self.inflight_done.wait_until(&mut inflight, deadline).timed_out() {
+                return self.get(&key);
+            }
+        }
+
+        inflight.insert(key.clone());
+        drop(inflight);
+
+        let _guard = InflightGuard {
+            key: &key,
+            inflight: &self.inflight,
+            done: &self.inflight_done,
+        };
+
+        let val = f();
+
+        let mut map = self.shard(&key).write();
+        Some(map.entry(key.clone()).or_insert(val).clone())
+    }
+
+    /// Cloned value and map length
+    ///
+    /// The value is read under its shard's lock and the length is counted
+    /// like `len`, so under concurrent writes the two may not describe the
+    /// same instant.
+    pub fn get_and_len(&self, key: &K) -> (Option<V>, usize)
+    where
+        V: Clone,
+    {
+        (self.get(key), self.len())
+    }
+
+    /// Run `f` over the entries for `keys` under one write lock
+    ///
+    /// `f` receives a map holding only the named keys that are present.
+    /// Whatever it leaves in that map is written back, so removals and
+    /// inserts made by `f` are applied atomically. If `f` panics, the
+    /// scoped map is still written back as `f` left it.
+    pub fn lock_keys<F, R>(&self, keys: &[K], f: F) -> R
+    where
+        F: FnOnce(&mut HashMap<K, V>) -> R,
+    {
+        let mut map = self.write_all();
+
+        let scoped: HashMap<K, V> = keys
+            .iter()
+            .filter_map(|k| map.remove_entry(k))
+            .collect();
+
+        let mut restore = RestoreGuard { map: &mut map, scoped };
+
+        f(&mut restore.scoped)
+    }
+
+    /// Remove entries whose value is within `[lo, hi]`, returning the count removed
+    pub fn remove_in_range(&self, lo: V, hi: V) -> usize
+    where
+        V: PartialOrd,
+    {
+        let mut map = self.write_all();
+
+        let before = map.len();
+        map.retain(|_, v| !(*v >= lo && *v <= hi));
+        before - map.len()
+    }
+
+    /// Cloned entries matching `f`
+    pub fn collect_where<F>(&self, mut f: F) -> Vec<(K, V)>
+    where
+        V: Clone,
+        F: FnMut(&K, &V) -> bool,
+    {
+        let map = self.read_all();
+
+        map.iter()
+            .filter(|(k, v)| f(k, v))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+
+    /// Number of keys mapping to each distinct value
+    pub fn value_frequencies(&self) -> HashMap<V, usize>
+    where
+        V: Eq + Hash + Clone,
+    {
+        let map = self.read_all();
+
+        let mut freq = HashMap::new();
+
+        for v in map.values() {
+            *freq.entry(v.clone()).or_insert(0) += 1;
+        }
+
+        freq
+    }
+
+    /// Visit entries in groups of `batch`, releasing the read locks between groups
+    ///
+    /// Keys are snapshotted up front: entries added mid-scan may be missed and
+    /// entries removed mid-scan are skipped.
+    pub fn for_each_yielding<F>(&self, batch: usize, mut f: F)
+    where
+        F: FnMut(&K, &V),
+    {
+        let keys: Vec<K> = self.read_all().keys().cloned().collect();
+
+        for chunk in keys.chunks(batch.max(1)) {
+            let map = self.read_all();
+
+            for k in chunk {
+                if let Some(v) = map.get(k) {
+                    f(k, v);
+                }
+            }
+        }
+    }
+
+    /// Remove and return the entry with the smallest value
+    pub fn pop_min(&self) -> Option<(K, V)>
+    where
+        V: Ord,
+    {
+        let mut map = self.write_all();
+
+        let key = map.iter().min_by(|a, b| a.1.cmp(b.1))?.0.clone();
+        map.remove_entry(&key)
+    }
+
+    /// Remove and return the entry with the largest value
+    pub fn pop_max(&self) -> Option<(K, V)>
+    where
+        V: Ord,
+    {
+        let mut map = self.write_all();
+
+        let key = map.iter().max_by(|a, b| a.1.cmp(b.1))?.0.clone();
+        map.remove_entry(&key)
+    }
+
+    /// Count keys matching `f`
+    pub fn count_keys<F>(&self, mut f: F) -> usize
+    where
+        F: FnMut(&K) -> bool,
+    {
+        let map = self.read_all();
+        map.keys().filter(|k| f(k)).count()
+    }
+
+    /// Replace all entries with the `Some` results of `f`
+    ///
+    /// If `f` maps several entries to the same key, which one survives is
+    /// unspecified.
+    pub fn rebuild<F>(&self, f: F)
+    where
+        F: Fn(K, V) -> Option<(K, V)>,
+    {
+        let mut map = self.write_all();
+
+        let rebuilt: Vec<(K, V)> = map.drain().into_iter().filter_map(|(k, v)| f(k, v)).collect();
+        map.extend(rebuilt);
+    }
+
+    /// Get the value, or derive one from the whole map and insert it
+    ///
+    /// `f` receives a [`MapView`] over every shard. Before sharding it took
+    /// `&HashMap<K, V, S>`; the view keeps the `HashMap` read methods such as
+    /// `get`, `iter` and `values`, so most closures only change their
+    /// parameter type.
+    pub fn get_or_derive<F>(&self, key: K, f: F) -> V
+    where
+        V: Clone,
+        F: FnOnce(&MapView<'_, K, V, S>) -> V,
+    {
+        let mut map = self.write_all();
+
+        if let Some(v) = map.get(&key) {
+            return v.clone();
+        }
+
+        let val = f(&MapView { shards: &map });
+        map.insert(key, val.clone());
+        val
+    }
+
+    /// Move the value at `from` to `to`, transforming it with `f`
+    ///
+    /// Any existing value at `to` is overwritten. Returns whether `from` existed.
+    pub fn remap<F>(&self, from: &K, to: K, f: F) -> bool
+    where
+        F: FnOnce(V) -> V,
+    {
+        let i = self.shard_index(from);
+        let j = self.shard_index(&to);
+
+        if i == j {
+            let mut map = self.shards[i].map.write();
+
+            let Some(val) = map.remove(from) else {
+                return false;
+            };
+
+            map.insert(to, f(val));
+            return true;
+        }
+
+        // Lock in index order, like the whole-map methods.
+        let (mut src, mut dst) = if i < j {
+            let src = self.shards[i].map.write();
+            (src, self.shards[j].map.write())
+        } else {
+            let dst = self.shards[j].map.write();
+            (self.shards[i].map.write(), dst)
+        };
+
+        let Some(val) = src.remove(from) else {
+            return false;
+        };
+
+        dst.insert(to, f(val));
+        true
+    }
+
+    /// Add each delta to its key, starting from `Default`
+    pub fn increment_many<I>(&self, deltas: I)
+    where
+        I: IntoIterator<Item = (K, V)>,
+        V: AddAssign + Default,
+    {
+        let mut map = self.write_all();
+
+        for (k, d) in deltas {
+            *map.entry(k).or_default() += d;
+        }
+    }
+
+    /// Run `present` on the value under a read lock, or `absent` after releasing it
+    pub fn map_or_else<R, F, G>(&self, key: &K, present: F, absent: G) -> R
+    where
+        F: FnOnce(&V) -> R,
+        G: FnOnce() -> R,
+    {
+        let map = self.read_shard(key);
+
+        match map.get(key) {
+            Some(v) => present(v),
+            None => {
+                drop(map);
+                absent()
+            }
+        }
+    }
+
+    /// Remove entries whose value reports empty via `is_empty`
+    ///
+    /// Shards are visited one at a time, like `retain`.
+    pub fn retain_nonempty<F>(&self, is_empty: F)
+    where
+        F: Fn(&V) -> bool,
+    {
+        self.retain(|_, v| !is_empty(v));
+    }
+
+    /// Drain all entries into `tx`, returning the count sent
+    ///
+    /// If the receiver hangs up, unsent entries are put back into the map.
+    pub fn drain_into_channel(&self, tx: &mpsc::Sender<(K, V)>) -> usize {
+        let mut map = self.write_all();
+
+        let mut entries = map.drain().into_iter();
+        let mut sent = 0;
+
+        for entry in entries.by_ref() {
+            if let Err(mpsc::SendError(entry)) = tx.send(entry) {
+                map.insert(entry.0, entry.1);
+                break;
+            }
+
+            sent += 1;
+        }
+
+        map.extend(entries);
+        sent
+    }
+
+    /// Whether every entry satisfies `f`; true for an empty map
+    pub fn all<F>(&self, mut f: F) -> bool
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        self.read_all().iter().all(|(k, v)| f(k, v))
+    }
+
+    /// Whether any entry satisfies `f`; false for an empty map
+    pub fn any<F>(&self, mut f: F) -> bool
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        self.read_all().iter().any(|(k, v)| f(k, v))
+    }
+
+    /// Order-independent hash of all entries
+    ///
+    /// Uses a fixed-key hasher so equal maps hash equally regardless of
+    /// their own hasher seeds.
+    pub fn content_hash(&self) -> u64
+    where
+        V: Hash,
+    {
+        let map = self.read_all();
+
+        map.iter().fold(0, |acc, entry| {
+            let mut h = DefaultHasher::new();
+            entry.hash(&mut h);
+            acc ^ h.finish()
+        })
+    }
+
+    /// Reserve `hint` extra capacity, then insert all items under one write lock
+    pub fn bulk_load<I>(&self, hint: usize, items: I)
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let mut map = self.write_all();
+
+        map.reserve(hint);
+        map.extend(items);
+    }
+
+    /// Keep only the `k` highest-valued entries, returning the number removed
+    ///
+    /// Ties at the boundary value are resolved arbitrarily.
+    pub fn truncate_top(&self, k: usize) -> usize
+    where
+        V: Ord,
+    {
+        let mut map = self.write_all();
+
+        if map.len() <= k {
+            return 0;
+        }
+
+        let mut ranked: Vec<(&K, &V)> = map.iter().collect();
+        ranked.sort_unstable_by(|a, b| b.1.cmp(a.1));
+
+        let evict: Vec<K> = ranked[k..].iter().map(|(k, _)| (*k).clone()).collect();
+
+        for key in &evict {
+            map.remove(key);
+        }
+
+        evict.len()
+    }
+
+    /// Increment each key (from `Default` if absent), returning the new values in input order
+    pub fn increment_snapshot(&self, keys: &[K]) -> Vec<V>
+    where
+        V: Copy + AddAssign + Default + From<u8>,
+    {
+        let mut map = self.write_all();
+
+        keys.iter()
+            .map(|k| {
+                let v = map.entry(k.clone()).or_default();
+                *v += V::from(1);
+                *v
+            })
+            .collect()
+    }
+
+    /// Remove and return all entries whose key starts with `prefix`
+    pub fn drain_prefix(&self, prefix: &str) -> Vec<(K, V)>
+    where
+        K: AsRef<str>,
+    {
+        let mut map = self.write_all();
+
+        let keys: Vec<K> = map
+            .keys()
+            .filter(|k| k.as_ref().starts_with(prefix))
+            .cloned()
+            .collect();
+
+        keys.iter().filter_map(|k| map.remove_entry(k)).collect()
+    }
+
+    /// Up to `n` cloned entries chosen uniformly by reservoir sampling
+    pub fn sample(&self, n: usize) -> Vec<(K, V)>
+    where
+        V: Clone,
+    {
+        let map = self.read_all();
+
+        let mut reservoir: Vec<(&K, &V)> = Vec::with_capacity(n.min(map.len()));
+
+        for (i, entry) in map.iter().enumerate() {
+            if i < n {
+                reservoir.push(entry);
+            } else {
+                let j = random_below(i + 1);
+
+                if j < n {
+                    reservoir[j] = entry;
+                }
+            }
+        }
+
+        reservoir
+            .into_iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+
+    /// Apply removals, then inserts, under one write lock
+    ///
+    /// Because removals run first, a key present in both lists ends up inserted.
+    pub fn apply_patch(&self, inserts: Vec<(K, V)>, removals: Vec<K>) {
+        let mut map = self.write_all();
+
+        for k in &removals {
+            map.remove(k);
+        }
+
+        map.extend(inserts);
+    }
+
+    /// Sum of `size_of` over all values
+    pub fn total_value_size<F>(&self, size_of: F) -> usize
+    where
+        F: Fn(&V) -> usize,
+    {
+        let map = self.read_all();
+        map.values().map(size_of).sum()
+    }
+
+    /// Insert `on_insert` if absent, otherwise apply `on_update`
+    pub fn insert_or_modify<F>(&self, key: K, on_insert: V, on_update: F)
+    where
+        F: FnOnce(&mut V),
+    {
+        let mut map = self.shard(&key).write();
+
+        match map.get_mut(&key) {
+            Some(v) => on_update(v),
+            None => {
+                map.insert(key, on_insert);
+            }
+        }
+    }
+
+    /// Iterator over a cloned snapshot of all entries
+    ///
+    /// The locks are released before this returns.
+    pub fn into_snapshot_iter(&self) -> std::vec::IntoIter<(K, V)>
+    where
+        V: Clone,
+    {
+        self.iter().into_iter()
+    }
+
+    /// Get the value or insert `f()`, reporting whether it was a miss
+    pub fn get_or_insert_with_stats<F>(&self, key: K, f: F) -> (V, bool)
+    where
+        V: Clone,
+        F: FnOnce() -> V,
+    {
+        let mut map = self.shard(&key).write();
+
+        if let Some(v) = map.get(&key) {
+            return (v.clone(), false);
+        }
+
+        let val = f();
+        map.insert(key, val.clone());
+        (val, true)
+    }
+
+    /// Decrement each key's count, removing and returning the keys that reach zero
+    ///
+    /// Missing keys are skipped. A stored count of zero or less is released
+    /// without being decremented, so unsigned counts cannot underflow.
+    pub fn release_many(&self, keys: &[K]) -> Vec<K>
+    where
+        V: Copy + PartialOrd + SubAssign + From<u8>,
+    {
+        let mut map = self.write_all();
+
+        let mut released = Vec::new();
+
+        for k in keys {
+            let Some(count) = map.get_mut(k) else {
+                continue;
+            };
+
+            if *count > V::from(1) {
+                *count -= V::from(1);
+            } else {
+                map.remove(k);
+                released.push(k.clone());
+            }
+        }
+
+        released
+    }
+
+    /// Point-in-time copy of all entries
+    pub fn iter(&self) -> Vec<(K, V)>
+    where
+        V: Clone,
+    {
+        let map = self.read_all();
+        map.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+    }
+
+    /// Point-in-time copy of all keys
+    pub fn keys(&self) -> Vec<K> {
+        let map = self.read_all();
+        map.keys().cloned().collect()
+    }
+
+    /// Point-in-time copy of all values
+    pub fn values(&self) -> Vec<V>
+    where
+        V: Clone,
+    {
+        let map = self.read_all();
+        map.values().cloned().collect()
+    }
+
+    /// Replace the contents of `buf` with cloned entries, reusing its allocation
+    pub fn snapshot_into(&self, buf: &mut Vec<(K, V)>)
+    where
+        V: Clone,
+    {
+        buf.clear();
+
+        let map = self.read_all();
+        buf.extend(map.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
+
+    /// Get a clone of the value, inserting `default` first if absent
+    ///
+    /// The lock is released before returning.
+    pub fn get_or_insert(&self, key: K, default: V) -> V
+    where
+        V: Clone,
+    {
+        self.get_or_insert_with(key, || default)
+    }
+
+    /// Get a clone of the value, inserting `f()` first if absent
+    ///
+    /// The lock is released before returning.
+    pub fn get_or_insert_with<F>(&self, key: K, f: F) -> V
+    where
+        V: Clone,
+        F: FnOnce() -> V,
+    {
+        let mut map = self.shard(&key).write();
+        map.entry(key).or_insert_with(f).clone()
+    }
+
+    /// Store `sample` if it is smaller than the current value or the key is absent
+    pub fn update_min(&self, key: K, sample: V)
+    where
+        V: Ord + Copy,
+    {
+        let mut map = self.shard(&key).write();
+
+        let cur = map.entry(key).or_insert(sample);
+        *cur = (*cur).min(sample);
+    }
+
+    /// Store `sample` if it is larger than the current value or the key is absent
+    pub fn update_max(&self, key: K, sample: V)
+    where
+        V: Ord + Copy,
+    {
+        let mut map = self.shard(&key).write();
+
+        let cur = map.entry(key).or_insert(sample);
+        *cur = (*cur).max(sample);
+    }
+
+    /// Map each entry through `f` under one read lock
+    pub fn project<R, F>(&self, mut f: F) -> Vec<R>
+    where
+        F: FnMut(&K, &V) -> R,
+    {
+        let map = self.read_all();
+        map.iter().map(|(k, v)| f(k, v)).collect()
+    }
+
+    /// Write guard to the value, inserting `V::default()` first if absent
+    ///
+    /// Holds the shard's write lock; see the deadlock note on [`CarbonMap`].
+    pub fn entry_default_mut(&self, key: K) -> MappedRwLockWriteGuard<'_, V>
+    where
+        V: Default,
+    {
+        RwLockWriteGuard::map(self.shard(&key).write(), |m| m.entry(key).or_default())
+    }
+
+    /// Remove the `n` smallest-valued entries, returned in ascending order
+    pub fn pop_min_n(&self, n: usize) -> Vec<(K, V)>
+    where
+        V: Ord + Clone,
+    {
+        let mut map = self.write_all();
+
+        if n == 0 || map.is_empty() {
+            return Vec::new();
+        }
+
+        let mut ranked: Vec<(&K, &V)> = map.iter().collect();
+
+        if n < ranked.len() {
+            ranked.select_nth_unstable_by(n - 1, |a, b| a.1.cmp(b.1));
+            ranked.truncate(n);
+        }
+
+        ranked.sort_unstable_by(|a, b| a.1.cmp(b.1));
+
+        let keys: Vec<K> = ranked.into_iter().map(|(k, _)| k.clone()).collect();
+
+        keys.iter().filter_map(|k| map.remove_entry(k)).collect()
+    }
+
+    /// Cloned values for each key, in input order
+    ///
+    /// Only the shards holding `keys` are locked, together, so the result
+    /// is a consistent snapshot without blocking on unrelated shards.
+    pub fn get_many<'a, I>(&self, keys: I) -> Vec<Option<V>>
+    where
+        I: IntoIterator<Item = &'a K>,
+        K: 'a,
+        V: Clone,
+    {
+        let keys: Vec<&K> = keys.into_iter().collect();
+
+        let map = self.read_keys(keys.iter().copied());
+        keys.iter().map(|k| map.get(k).cloned()).collect()
+    }
+
+    /// Keys of all entries for which `check` returns false
+    pub fn validate<F>(&self, mut check: F) -> Vec<K>
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        let map = self.read_all();
+
+        map.iter()
+            .filter(|(k, v)| !check(k, v))
+            .map(|(k, _)| k.clone())
+            .collect()
+    }
+
+    /// Replace the entry with `f(current)`, removing it if `f` returns `None`
+    pub fn compute<F>(&self, key: K, f: F)
+    where
+        F: FnOnce(Option<V>) -> Option<V>,
+    {
+        let mut map = self.shard(&key).write();
+
+        let cur = map.remove(&key);
+
+        if let Some(v) = f(cur) {
+            map.insert(key, v);
+        }
+    }
+
+    /// Like `compute`, but only runs `f` if the key is present
+    ///
+    /// Returns whether the key was present.
+    pub fn compute_if_present<F>(&self, key: &K, f: F) -> bool
+    where
+        F: FnOnce(V) -> Option<V>,
+    {
+        let mut map = self.shard(key).write();
+
+        let Some((k, v)) = map.remove_entry(key) else {
+            return false;
+        };
+
+        if let Some(v) = f(v) {
+            map.insert(k, v);
+        }
+
+        true
+    }
+
+    /// Apply `transform` to every value selected by `select` under one write lock
+    ///
+    /// Returns the count transformed.
+    pub fn transform_where<F, G>(&self, mut select: G, mut transform: F) -> usize
+    where
+        G: FnMut(&K, &V) -> bool,
+        F: FnMut(&mut V),
+    {
+        let mut map = self.write_all();
+
+        let mut n = 0;
+
+        for (k, v) in map.iter_mut() {
+            if select(k, v) {
+                transform(v);
+                n += 1;
+            }
+        }
+
+        n
+    }
+
+    /// Cloned entries sorted by value, ties broken by key
+    pub fn sorted_by_value(&self) -> Vec<(K, V)>
+    where
+        K: Ord,
+        V: Ord + Clone,
+    {
+        let mut entries = self.iter();
+        entries.sort_unstable_by(|a, b| (&a.1, &a.0).cmp(&(&b.1, &b.0)));
+        entries
+    }
+
+    /// Insert `val` under the key derived by `key_fn`, returning that key
+    pub fn insert_keyed<F>(&self, val: V, key_fn: F) -> K
+    where
+        F: FnOnce(&V) -> K,
+    {
+        let key = key_fn(&val);
+
+        let mut map = self.shard(&key).write();
+        map.insert(key.clone(), val);
+        key
+    }
+
+    /// Insert `val` only if `key` is absent, returning whether this was the first occurrence
+    pub fn record_first(&self, key: K, val: V) -> bool {
+        self.try_claim(key, val)
+    }
+
+    /// Operations that would make the map equal `desired`
+    ///
+    /// Returns `(to_insert, to_update, to_delete)`; apply them with `apply_patch`.
+    pub fn reconcile_plan(&self, desired: &HashMap<K, V>) -> ReconcilePlan<K, V>
+    where
+        V: Clone + PartialEq,
+    {
+        let map = self.read_all();
+
+        let mut to_insert = Vec::new();
+        let mut to_update = Vec::new();
+
+        for (k, want) in desired {
+            match map.get(k) {
+                None => to_insert.push((k.clone(), want.clone())),
+                Some(have) if have != want => to_update.push((k.clone(), want.clone())),
+                Some(_) => {}
+            }
+        }
+
+        let to_delete = map
+            .keys()
+            .filter(|k| !desired.contains_key(*k))
+            .cloned()
+            .collect();
+
+        (to_insert, to_update, to_delete)
+    }
+
+    /// Make the map equal `desired` under one write lock
+    pub fn reconcile(&self, desired: HashMap<K, V>) -> ReconcileStats
+    where
+        V: PartialEq,
+    {
+        let mut map = self.write_all();
+
+        let before = map.len();
+        map.retain(|k, _| desired.contains_key(k));
+
+        let mut stats = ReconcileStats {
+            deleted: before - map.len(),
+            ..ReconcileStats::default()
+        };
+
+        for (k, want) in desired {
+            match map.get_mut(&k) {
+                None => {
+                    map.insert(k, want);
+                    stats.inserted += 1;
+                }
+                Some(have) if *have != want => {
+                    *have = want;
+                    stats.updated += 1;
+                }
+                Some(_) => {}
+            }
+        }
+
+        stats
+    }
+}
+
+/* ================= Specialized Impl ================= */
+
+impl<K, S> CarbonMap<K, (u64, f64), S>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher + Clone,
+{
+    /// Record a sample in a `(count, sum)` entry, creating it if absent
+    pub fn update_average(&self, key: K, sample: f64) {
+        let mut map = self.shard(&key).write();
+
+        let (count, sum) = map.entry(key).or_insert((0, 0.0));
+        *count += 1;
+        *sum += sample;
+    }
+
+    /// Mean of the samples recorded for `key`
+    pub fn average(&self, key: &K) -> Option<f64> {
+        let map = self.read_shard(key);
+
+        match map.get(key)? {
+            (0, _) => None,
+            (count, sum) => Some(sum / *count as f64),
+        }
+    }
+}
+
+impl<K, V, S> CarbonMap<K, (V, u64), S>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher + Clone,
+{
+    /// Insert only if `ts` is newer than the stored timestamp
+    ///
+    /// Returns whether the write won. Equal timestamps lose.
+    pub fn lww_insert(&self, key: K, val: V, ts: u64) -> bool {
+        let mut map = self.shard(&key).write();
+
+        match map.get_mut(&key) {
+            Some((_, cur)) if *cur >= ts => false,
+            Some(slot) => {
+                *slot = (val, ts);
+                true
+            }
+            None => {
+                map.insert(key, (val, ts));
+                true
+            }
+        }
+    }
+}
+
+impl<K, S> CarbonMap<K, bool, S>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher + Clone,
+{
+    /// Set the flag, returning its prior value (`false` if absent)
+    pub fn test_and_set(&self, key: K) -> bool {
+        let mut map = self.shard(&key).write();
+        map.insert(key, true).unwrap_or(false)
+    }
+}
+
+impl<K, V, S> CarbonMap<K, Arc<V>, S>
+where
+    K: Eq + Hash + Clone,
+    V: Eq + Hash,
+    S: BuildHasher + Clone,
+{
+    /// Make equal values share a single `Arc`, returning how many were replaced
+    pub fn dedup_arcs(&self) -> usize {
+        let mut map = self.write_all();
+
+        let mut seen: HashSet<Arc<V>> = HashSet::new();
+        let mut replaced = 0;
+
+        for (_, v) in map.iter_mut() {
+            match seen.get(&**v) {
+                Some(shared) if !Arc::ptr_eq(shared, v) => {
+                    *v = shared.clone();
+                    replaced += 1;
+                }
+                Some(_) => {}
+                None => {
+                    seen.insert(v.clone());
+                }
+            }
+        }
+
+        replaced
+    }
+}
+
+/* ================= Trait Impls ================= */
+
+impl<K, V, S> Default for CarbonMap<K, V, S>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher + Clone + Default,
+{
+    fn default() -> Self {
+        Self::with_hasher(S::default())
+    }
+}
+
+impl<K, V, S> FromIterator<(K, V)> for CarbonMap<K, V, S>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher + Clone + Default,
+{
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut map = Self::default();
+        map.extend(iter);
+        map
+    }
+}
+
+impl<K, V, S> Extend<(K, V)> for CarbonMap<K, V, S>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher + Clone,
+{
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        for (k, v) in iter {
+            let i = self.shard_index(&k);
+            self.shards[i].map.get_mut().insert(k, v);
+        }
+    }
+}
+
+impl<K, V, S> Extend<(K, V)> for &CarbonMap<K, V, S>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher + Clone,
+{
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        self.write_all().extend(iter);
+    }
+}
+
+/// Clones a consistent snapshot taken with every shard read-locked
+///
+/// The copy is fully independent and keeps the shard count and the
+/// fair-locking setting.
+impl<K, V, S> Clone for CarbonMap<K, V, S>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+    S: BuildHasher + Clone,
+{
+    fn clone(&self) -> Self {
+        let src = self.read_all();
+        let mut copy = Self::build(self.hasher.clone(), self.shards.len(), 0, self.fair);
+
+        for (dst, shard) in copy.shards.iter_mut().zip(&src.guards) {
+            *dst.map.get_mut() = (**shard).clone();
+        }
+
+        copy
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<K, V, S> serde::Serialize for CarbonMap<K, V, S>
+where
+    K: serde::Serialize + Eq + Hash + Clone,
+    V: serde::Serialize,
+    S: BuildHasher + Clone,
+{
+    fn serialize<Ser: serde::Serializer>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error> {
+        let map = self.read_all();
+        serializer.collect_map(map.iter())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, K, V, S> serde::Deserialize<'de> for CarbonMap<K, V, S>
+where
+    K: serde::Deserialize<'de> + Eq + Hash + Clone,
+    V: serde::Deserialize<'de>,
+    S: BuildHasher + Clone + Default,
+{
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let map = <HashMap<K, V, S> as serde::Deserialize>::deserialize(deserializer)?;
+        Ok(Self::from_map(map, false))
+    }
+}
+
+/* ================= Entry Impl ================= */
+
+impl<'a, K, V, S> Entry<'a, K, V, S>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher,
+{
+    pub fn key(&self) -> &K {
+        match self {
+            Entry::Occupied(e) => e.key(),
+            Entry::Vacant(e) => e.key(),
+        }
+    }
+
+    pub fn or_insert(self, default: V) -> MappedRwLockWriteGuard<'a, V> {
+        match self {
+            Entry::Occupied(e) => e.into_guard(),
+            Entry::Vacant(e) => e.insert(default),
+        }
+    }
+
+    pub fn or_insert_with<F>(self, f: F) -> MappedRwLockWriteGuard<'a, V>
+    where
+        F: FnOnce() -> V,
+    {
+        match self {
+            Entry::Occupied(e) => e.into_guard(),
+            Entry::Vacant(e) => e.insert(f()),
+        }
+    }
+
+    pub fn and_modify<F>(self, f: F) -> Self
+    where
+        F: FnOnce(&mut V),
+    {
+        match self {
+            Entry::Occupied(mut e) => {
+                f(e.get_mut());
+                Entry::Occupied(e)
+            }
+            e => e,
+        }
+    }
+}
+
+impl<'a, K, V, S> OccupiedEntry<'a, K, V, S>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher,
+{
+    fn into_guard(self) -> MappedRwLockWriteGuard<'a, V> {
+        let val: *mut V = self.inner.into_mut();
+
+        // SAFETY: `val` points into the map that `guard` keeps locked.
+        RwLockWriteGuard::map(self.guard, |_| unsafe { &mut *val })
+    }
+
+    pub fn key(&self) -> &K {
+        self.inner.key()
+    }
+
+    pub fn get(&self) -> &V {
+        self.inner.get()
+    }
+
+    pub fn get_mut(&mut self) -> &mut V {
+        self.inner.get_mut()
+    }
+
+    /// Replace the value, returning the old one
+    pub fn insert(&mut self, val: V) -> V {
+        self.inner.insert(val)
+    }
+
+    /// Remove the entry from the map, returning its value
+    pub fn remove(self) -> V {
+        self.inner.remove()
+    }
+}
+
+impl<'a, K, V, S> VacantEntry<'a, K, V, S>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher,
+{
+    pub fn key(&self) -> &K {
+        self.inner.key()
+    }
+
+    /// Take back the key without inserting, releasing the lock
+    pub fn into_key(self) -> K {
+        self.inner.into_key()
+    }
+
+    pub fn insert(self, val: V) -> MappedRwLockWriteGuard<'a, V> {
+        let val: *mut V = self.inner.insert(val);
+
+        // SAFETY: `val` points into the map that `guard` keeps locked.
+        RwLockWriteGuard::map(self.guard, |_| unsafe { &mut *val })
+    }
+}
+
+impl<'a, 'q, K, Q, V, S> EntryRef<'a, 'q, K, Q, V, S>
+where
+    K: Eq + Hash + Borrow<Q>,
+    Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    S: BuildHasher,
+{
+    pub fn key(&self) -> &Q {
+        match self {
+            EntryRef::Occupied(e) => e.key(),
+            EntryRef::Vacant(e) => e.key(),
+        }
+    }
+
+    pub fn or_insert(self, default: V) -> MappedRwLockWriteGuard<'a, V> {
+        match self {
+            EntryRef::Occupied(e) => e.into_guard(),
+            EntryRef::Vacant(e) => e.insert(default),
+        }
+    }
+
+    pub fn or_insert_with<F>(self, f: F) -> MappedRwLockWriteGuard<'a, V>
+    where
+        F: FnOnce() -> V,
+    {
+        match self {
+            EntryRef::Occupied(e) => e.into_guard(),
+            EntryRef::Vacant(e) => e.insert(f()),
+        }
+    }
+
+    pub fn and_modify<F>(self, f: F) -> Self
+    where
+        F: FnOnce(&mut V),
+    {
+        match self {
+            EntryRef::Occupied(mut e) => {
+                f(e.get_mut());
+                EntryRef::Occupied(e)
+            }
+            e => e,
+        }
+    }
+}
+
+impl<'a, 'q, K, Q, V, S> OccupiedEntryRef<'a, 'q, K, Q, V, S>
+where
+    K: Eq + Hash + Borrow<Q>,
+    Q: Hash + Eq + ?Sized,
+    S: BuildHasher,
+{
+    fn into_guard(self) -> MappedRwLockWriteGuard<'a, V> {
+        let val = self.val;
+
+        // SAFETY: see the field comment on `val`.
+        RwLockWriteGuard::map(self.guard, |_| unsafe { &mut *val })
+    }
+
+    pub fn key(&self) -> &Q {
+        self.key
+    }
+
+    pub fn get(&self) -> &V {
+        // SAFETY: see the field comment on `val`.
+        unsafe { &*self.val }
+    }
+
+    pub fn get_mut(&mut self) -> &mut V {
+        // SAFETY: see the field comment on `val`.
+        unsafe { &mut *self.val }
+    }
+
+    /// Replace the value, returning the old one
+    pub fn insert(&mut self, val: V) -> V {
+        std::mem::replace(self.get_mut(), val)
+    }
+
+    /// Remove the entry from the map, returning its value
+    pub fn remove(mut self) -> V {
+        self.guard.remove(self.key).unwrap()
+    }
+}
+
+// SAFETY: `val` only points into the map owned by `guard`, and a shared
+// `&OccupiedEntryRef` only hands out `&V` and `&Q`. That is the access a shared
+// `&RwLockWriteGuard` plus the borrowed key already give.
+unsafe impl<K: Sync, Q: Sync + ?Sized, V: Sync, S: Sync> Sync for OccupiedEntryRef<'_, '_, K, Q, V, S> {}
+
+impl<'a, 'q, K, Q, V, S> VacantEntryRef<'a, 'q, K, Q, V, S>
+where
+    K: Eq + Hash + Borrow<Q>,
+    Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    S: BuildHasher,
+{
+    pub fn key(&self) -> &Q {
+        self.key
+    }
+
+    /// Insert `val` under an owned copy of the key
+    ///
+    /// std has no borrowed-key entry API, so this hashes the key a second time.
+    pub fn insert(self, val: V) -> MappedRwLockWriteGuard<'a, V> {
+        let key = self.key.to_owned();
+
+        RwLockWriteGuard::map(self.guard, |m| m.entry(key).or_insert(val))
+    }
+}
+
+/* ================= Audited Map ================= */
+
+/// Map that keeps a bounded history of prior values per key
+///
+/// Each key stores up to `cap` previous values alongside the current one,
+/// so memory grows to roughly `cap + 1` values per key.
+pub struct AuditedCarbonMap<K, V> {
+    map: CarbonMap<K, (V, VecDeque<V>)>,
+    cap: usize,
+}
+
+impl<K, V> AuditedCarbonMap<K, V>
+where
+    K: Eq + Hash + Clone,
+{
+    /// New map retaining at most `cap` prior values per key
+    pub fn new(cap: usize) -> Self {
+        Self {
+            map: CarbonMap::new(),
+            cap,
+        }
+    }
+
+    /// Insert or overwrite, recording the prior value
+    pub fn insert(&self, key: K, val: V) {
+        let mut map = self.map.shard(&key).write();
+
+        match map.get_mut(&key) {
+            Some((cur, hist)) => {
+                let old = std::mem::replace(cur, val);
+                Self::record(hist, old, self.cap);
+            }
+            None => {
+                map.insert(key, (val, VecDeque::new()));
+            }
+        }
+    }
+
+    /// Modify in place, recording the prior value
+    ///
+    /// Returns whether the key existed.
+    pub fn update<F>(&self, key: &K, f: F) -> bool
+    where
+        V: Clone,
+        F: FnOnce(&mut V),
+    {
+        let mut map = self.map.shard(key).write();
+
+        let Some((cur, hist)) = map.get_mut(key) else {
+            return false;
+        };
+
+        let old = cur.clone();
+        f(cur);
+        Self::record(hist, old, self.cap);
+        true
+    }
+
+    /// Get cloned current value
+    pub fn get(&self, key: &K) -> Option<V>
+    where
+        V: Clone,
+    {
+        let map = self.map.read_shard(key);
+        map.get(key).map(|(v, _)| v.clone())
+    }
+
+    /// Prior values, oldest first
+    pub fn history(&self, key: &K) -> Option<Vec<V>>
+    where
+        V: Clone,
+    {
+        let map = self.map.read_shard(key);
+        map.get(key).map(|(_, h)| h.iter().cloned().collect())
+    }
+
+    /// Remove key and its history, returning the current value
+    pub fn remove(&self, key: &K) -> Option<V> {
+        self.map.remove(key).map(|(v, _)| v)
+    }
+
+    fn record(hist: &mut VecDeque<V>, old: V, cap: usize) {
+        if cap == 0 {
+            return;
+        }
+
+        if hist.len() == cap {
+            hist.pop_front();
+        }
+
+        hist.push_back(old);
+    }
+}
+
+/* ================= Free Functions ================= */
+
+/// Read `key` from two maps with its shard in each locked at once
+///
+/// The maps are locked in address order. Passing the same map twice takes
+/// its shard's lock only once.
+pub fn join_get<K, V, W, S>(
+    a: &CarbonMap<K, V, S>,
+    b: &CarbonMap<K, W, S>,
+    key: &K,
+) -> (Option<V>, Option<W>)
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher + Clone,
+    V: Clone,
+    W: Clone,
+{
+    if std::ptr::eq(a as *const _ as *const (), b as *const _ as *const ()) {
+        let ga = a.read_shard(key);
+
+        // SAFETY: `b` is `a`, so `ga` already holds the read lock on this
+        // shard and the data may be read through `b`'s view of it.
+        let gb = unsafe { &*b.shard(key).data_ptr() };
+
+        return (ga.get(key).cloned(), gb.get(key).cloned());
+    }
+
+    let (ga, gb) = if locks_first(a, b) {
+        let ga = a.read_shard(key);
+        (ga, b.read_shard(key))
+    } else {
+        let gb = b.read_shard(key);
+        (a.read_shard(key), gb)
+    };
+
+    (ga.get(key).cloned(), gb.get(key).cloned())
+}
+
+/* ================= Helpers ================= */
+
+type ShardWriteGuard<'a, K, V, S> = RwLockWriteGuard<'a, HashMap<K, V, S>>;
+type ReadShards<'a, K, V, S> = Shards<'a, K, V, S, ReadGuard<'a, K, V, S>>;
+type WriteShards<'a, K, V, S> = Shards<'a, K, V, S, ShardWriteGuard<'a, K, V, S>>;
+
+/// One shard's lock and reader count, on its own cache line
+///
+/// The count sits beside the lock word every read already writes, so
+/// tracking it touches no extra line. 128 bytes covers CPUs that fetch
+/// cache lines in adjacent pairs.
+#[repr(align(128))]
+struct Shard<K, V, S> {
+    map: RwLock<HashMap<K, V, S>>,
+    readers: AtomicUsize,
+}
+
+/// Read guard that tracks its shard's reader count and honours fair unlocking
+struct ReadGuard<'a, K, V, S> {
+    guard: Option<RwLockReadGuard<'a, HashMap<K, V, S>>>,
+    readers: &'a AtomicUsize,
+    fair: bool,
+}
+
+impl<K, V, S> Deref for ReadGuard<'_, K, V, S> {
+    type Target = HashMap<K, V, S>;
+
+    fn deref(&self) -> &HashMap<K, V, S> {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<K, V, S> Drop for ReadGuard<'_, K, V, S> {
+    fn drop(&mut self) {
+        self.readers.fetch_sub(1, Ordering::Relaxed);
+
+        if let Some(guard) = self.guard.take() {
+            if self.fair {
+                RwLockReadGuard::unlock_fair(guard);
+            }
+        }
+    }
+}
+
+/// Every shard of one map, locked in index order
+///
+/// Routes each key to its own shard, so whole-map methods can treat the
+/// guards as a single table.
+struct Shards<'a, K, V, S, G> {
+    map: &'a CarbonMap<K, V, S>,
+    guards: Vec<G>,
+}
+
+impl<K, V, S, G> Shards<'_, K, V, S, G>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher + Clone,
+    G: Deref<Target = HashMap<K, V, S>>,
+{
+    fn get(&self, key: &K) -> Option<&V> {
+        self.guards[self.map.shard_index(key)].get(key)
+    }
+
+    fn contains_key(&self, key: &K) -> bool {
+        self.guards[self.map.shard_index(key)].contains_key(key)
+    }
+
+    fn len(&self) -> usize {
+        self.guards.iter().map(|g| g.len()).sum()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.guards.iter().all(|g| g.is_empty())
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.guards.iter().flat_map(|g| g.iter())
+    }
+
+    fn keys(&self) -> impl Iterator<Item = &K> {
+        self.guards.iter().flat_map(|g| g.keys())
+    }
+
+    fn values(&self) -> impl Iterator<Item = &V> {
+        self.guards.iter().flat_map(|g| g.values())
+    }
+}
+
+impl<K, V, S, G> Shards<'_, K, V, S, G>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher + Clone,
+    G: DerefMut<Target = HashMap<K, V, S>>,
+{
+    fn shard_mut(&mut self, key: &K) -> &mut HashMap<K, V, S> {
+        let i = self.map.shard_index(key);
+        &mut self.guards[i]
+    }
+
+    fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        self.shard_mut(key).get_mut(key)
+    }
+
+    fn entry(&mut self, key: K) -> hash_map::Entry<'_, K, V> {
+        self.shard_mut(&key).entry(key)
+    }
+
+    fn insert(&mut self, key: K, val: V) -> Option<V> {
+        self.shard_mut(&key).insert(key, val)
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        self.shard_mut(key).remove(key)
+    }
+
+    fn remove_entry(&mut self, key: &K) -> Option<(K, V)> {
+        self.shard_mut(key).remove_entry(key)
+    }
+
+    fn iter_mut(&mut self) -> impl Iterator<Item = (&K, &mut V)> {
+        self.guards.iter_mut().flat_map(|g| g.iter_mut())
+    }
+
+    fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&K, &mut V) -> bool,
+    {
+        for g in &mut self.guards {
+            g.retain(&mut f);
+        }
+    }
+
+    fn clear(&mut self) {
+        for g in &mut self.guards {
+            g.clear();
+        }
+    }
+
+    /// Reserve room for `additional` entries, split evenly across shards
+    fn reserve(&mut self, additional: usize) {
+        let per_shard = additional.div_ceil(self.guards.len());
+
+        for g in &mut self.guards {
+            g.reserve(per_shard);
+        }
+    }
+
+    fn drain(&mut self) -> Vec<(K, V)> {
+        self.guards.iter_mut().flat_map(|g| g.drain()).collect()
+    }
+
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        for (k, v) in iter {
+            self.insert(k, v);
+        }
+    }
+}
+
+/// The shards holding a known set of keys, read-locked in ascending index order
+struct KeyShards<'a, K, V, S> {
+    map: &'a CarbonMap<K, V, S>,
+    guards: Vec<(usize, ReadGuard<'a, K, V, S>)>,
+}
+
+impl<K, V, S> KeyShards<'_, K, V, S>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher + Clone,
+{
+    /// Look up one of the keys the shards were locked for
+    fn get(&self, key: &K) -> Option<&V> {
+        let i = self.map.shard_index(key);
+
+        let pos = self
+            .guards
+            .binary_search_by_key(&i, |(j, _)| *j)
+            .expect("key's shard was not locked");
+
+        self.guards[pos].1.get(key)
+    }
+}
+
+/// Clears an in-flight key and wakes waiters, even if the computation panics
+struct InflightGuard<'a, K: Eq + Hash> {
+    key: &'a K,
+    inflight: &'a Mutex<HashSet<K>>,
+    done: &'a Condvar,
+}
+
+impl<K: Eq + Hash> Drop for InflightGuard<'_, K> {
+    fn drop(&mut self) {
+        self.inflight.lock().remove(self.key);
+        self.done.notify_all();
+    }
+}
+
+/// Writes the entries lifted out by `lock_keys` back, even if its closure panics
+struct RestoreGuard<'a, 'b, K, V, S>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher + Clone,
+{
+    map: &'b mut WriteShards<'a, K, V, S>,
+    scoped: HashMap<K, V>,
+}
+
+impl<K, V, S> Drop for RestoreGuard<'_, '_, K, V, S>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher + Clone,
+{
+    fn drop(&mut self) {
+        self.map.extend(self.scoped.drain());
+    }
+}
+
+/// One shard per available core, rounded up to a power of two and capped at 16
+///
+/// Computed once: `available_parallelism` can read cgroup files on Linux.
+fn default_shard_count() -> usize {
+    static COUNT: OnceLock<usize> = OnceLock::new();
+
+    *COUNT.get_or_init(|| {
+        let cores = std::thread::available_parallelism().map_or(1, usize::from);
+        cores.next_power_of_two().min(16)
+    })
+}
+
+/// Whether `a` is locked before `b` when both are needed, by address
+fn locks_first<A, B>(a: &A, b: &B) -> bool {
+    (a as *const A as usize) < (b as *const B as usize)
+}
+
+/// Write-lock every shard of two distinct maps, in address order to avoid deadlock
+fn write_pair<'a, K, V, S>(
+    a: &'a CarbonMap<K, V, S>,
+    b: &'a CarbonMap<K, V, S>,
+) -> (WriteShards<'a, K, V, S>, WriteShards<'a, K, V, S>)
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher + Clone,
+{
+    if locks_first(a, b) {
+        let ga = a.write_all();
+        let gb = b.write_all();
+        (ga, gb)
+    } else {
+        let gb = b.write_all();
+        let ga = a.write_all();
+        (ga, gb)
+    }
+}
+
+/// Read-lock every shard of two distinct maps, in address order to avoid deadlock
+fn read_pair<'a, K, V, W, S>(
+    a: &'a CarbonMap<K, V, S>,
+    b: &'a CarbonMap<K, W, S>,
+) -> (ReadShards<'a, K, V, S>, ReadShards<'a, K, W, S>)
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher + Clone,
+{
+    if locks_first(a, b) {
+        let ga = a.read_all();
+        let gb = b.read_all();
+        (ga, gb)
+    } else {
+        let gb = b.read_all();
+        let ga = a.read_all();
+        (ga, gb)
+    }
+}
+
+/// Uniform random index in `0..n` from a thread-local xorshift generator
+fn random_below(n: usize) -> usize {
+    thread_local! {
+        static STATE: Cell<u64> = Cell::new(RandomState::new().build_hasher().finish() | 1);
+    }
+
+    let x = STATE.with(|s| {
+        let mut x = s.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        s.set(x);
+        x
+    });
+
+    (x % n as u64) as usize
+}
+
+/* ================= Tests ================= */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn capacity<K, V, S>(map: &CarbonMap<K, V, S>) -> usize {
+        map.shards.iter().map(|s| s.map.read().capacity()).sum()
+    }
+
+    #[test]
+    fn basic_insert_get() {
+        let map = CarbonMap::new();
+
+        map.insert("a", 1);
+        map.insert("b", 2);
+
+        assert_eq!(map.get(&"a"), Some(1));
+        assert_eq!(map.get(&"b"), Some(2));
+        assert_eq!(map.get(&"c"), None);
+    }
+
+    #[test]
+    fn overwrite() {
+        let map = CarbonMap::new();
+
+        map.insert("x", 10);
+        map.insert("x", 20);
+
+        assert_eq!(map.get(&"x"), Some(20));
+    }
+
+    #[test]
+    fn remove_basic() {
+        let map = CarbonMap::new();
+
+        map.insert("a", 1);
+
+        let v = map.remove(&"a");
+
+        assert_eq!(v, Some(1));
+        assert_eq!(map.get(&"a"), None);
+    }
+
+    #[test]
+    fn entry_or_insert() {
+        let map = CarbonMap::new();
+
+        let v = map.entry("a").or_insert(10);
+
+        assert_eq!(*v, 10);
+    }
+
+    #[test]
+    fn entry_and_modify() {
+        let map = CarbonMap::new();
+
+        let _ = map.entry("a").or_insert(1);
+
+        let _ = map.entry("a")
+            .and_modify(|v| *v += 5)
+            .or_insert(0);
+
+        assert_eq!(map.get(&"a"), Some(6));
+    }
+
+    #[test]
+    fn entry_or_insert_with() {
+        let map = CarbonMap::new();
+
+        let v = map.entry("k").or_insert_with(|| 42);
+
+        assert_eq!(*v, 42);
+    }
+
+    #[test]
+    fn concurrent_inserts() {
+        let map = Arc::new(CarbonMap::new());
+
+        let mut handles = vec![];
+
+        for i in 0..8 {
+            let m = map.clone();
+
+            handles.push(thread::spawn(move || {
+                for j in 0..1000 {
+                    let k = format!("{}-{}", i, j);
+                    m.insert(k, j);
+                }
+            }));
+        }
+
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        assert!(map.get(&"0-0".to_string()).is_some());
+        assert!(map.get(&"7-999".to_string()).is_some());
+    }
+
+    #[test]
+    fn concurrent_entry_updates() {
+        let map = Arc::new(CarbonMap::new());
+
+        let mut handles = vec![];
+
+        for _ in 0..10 {
+            let m = map.clone();
+
+            handles.push(thread::spawn(move || {
+                for _ in 0..1000 {
+                    let _ = m.entry("counter")
+                        .and_modify(|v| *v += 1)
+                        .or_insert(1);
+                }
+            }));
+        }
+
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        let val = map.get(&"counter");
+
+        assert_eq!(val, Some(10000));
+    }
+
+    #[test]
+    fn take_batch_drains() {
+        let map = CarbonMap::new();
+
+        for i in 0..100 {
+            map.insert(i, i);
+        }
+
+        let mut seen = std::collections::HashSet::new();
+
+        loop {
+            let batch = map.take_batch(7);
+
+            if batch.is_empty() {
+                break;
+            }
+
+            assert!(batch.len() <= 7);
+
+            for (k, v) in batch {
+                assert_eq!(k, v);
+                assert!(seen.insert(k));
+            }
+        }
+
+        assert_eq!(seen.len(), 100);
+        assert_eq!(map.get(&0), None);
+    }
+
+    #[test]
+    fn concurrent_try_claim() {
+        let map = Arc::new(CarbonMap::new());
+
+        let mut handles = vec![];
+
+        for i in 0..16 {
+            let m = map.clone();
+
+            handles.push(thread::spawn(move || m.try_claim("job", i)));
+        }
+
+        let wins = handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .filter(|&won| won)
+            .count();
+
+        assert_eq!(wins, 1);
+        assert!(map.get(&"job").is_some());
+    }
+
+    #[test]
+    fn try_update_rolls_back() {
+        let map = CarbonMap::new();
+
+        map.insert("a", vec![1, 2]);
+
+        let res: Result<bool, &str> = map.try_update(&"a", |v| {
+            v.push(3);
+            Err("boom")
+        });
+
+        assert_eq!(res, Err("boom"));
+        assert_eq!(map.get(&"a"), Some(vec![1, 2]));
+
+        let res: Result<bool, &str> = map.try_update(&"a", |v| {
+            v.push(3);
+            Ok(())
+        });
+
+        assert_eq!(res, Ok(true));
+        assert_eq!(map.get(&"a"), Some(vec![1, 2, 3]));
+
+        let res: Result<bool, &str> = map.try_update(&"b", |_| Ok(()));
+
+        assert_eq!(res, Ok(false));
+    }
+
+    #[test]
+    fn apply_updates_per_key() {
+        let map = CarbonMap::new();
+
+        map.insert("a", 1);
+        map.insert("b", 10);
+
+        let mut updates: HashMap<&str, fn(&mut i32)> = HashMap::new();
+        updates.insert("a", |v| *v += 1);
+        updates.insert("b", |v| *v += 5);
+        updates.insert("c", |v| *v += 100);
+
+        map.apply_updates(updates);
+
+        assert_eq!(map.get(&"a"), Some(2));
+        assert_eq!(map.get(&"b"), Some(15));
+        assert_eq!(map.get(&"c"), None);
+    }
+
+    #[test]
+    fn clear_counted_returns_len() {
+        let map = CarbonMap::new();
+
+        for i in 0..50 {
+            map.insert(i, i);
+        }
+
+        assert_eq!(map.clear_counted(), 50);
+        assert_eq!(map.get(&0), None);
+        assert_eq!(map.clear_counted(), 0);
+    }
+
+    #[test]
+    fn percentile_known_set() {
+        let map = CarbonMap::new();
+
+        assert_eq!(map.percentile(0.5), None);
+
+        for i in 1..=11 {
+            map.insert(i, i * 10);
+        }
+
+        assert_eq!(map.percentile(0.5), Some(60));
+        assert_eq!(map.percentile(0.9), Some(100));
+        assert_eq!(map.percentile(-1.0), Some(10));
+        assert_eq!(map.percentile(2.0), Some(110));
+    }
+
+    #[test]
+    fn migrate_where_moves_matching() {
+        let hot = CarbonMap::new();
+        let cold = CarbonMap::new();
+
+        for i in 0..20 {
+            hot.insert(i, i);
+        }
+
+        let moved = hot.migrate_where(&cold, |_, v| *v >= 15);
+
+        assert_eq!(moved, 5);
+
+        for i in 0..20 {
+            let in_hot = hot.get(&i).is_some();
+            let in_cold = cold.get(&i).is_some();
+
+            assert!(in_hot ^ in_cold);
+            assert_eq!(in_cold, i >= 15);
+        }
+
+        assert_eq!(hot.migrate_where(&hot, |_, _| true), 0);
+    }
+
+    #[test]
+    fn compact_shrinks_capacity() {
+        let map = CarbonMap::new();
+
+        for i in 0..10_000 {
+            map.insert(i, i);
+        }
+
+        for i in 10..10_000 {
+            map.remove(&i);
+        }
+
+        let before = capacity(&map);
+
+        map.compact();
+
+        let after = capacity(&map);
+
+        assert!(after < before);
+
+        for i in 0..10 {
+            assert_eq!(map.get(&i), Some(i));
+        }
+    }
+
+    #[test]
+    fn get_first_prefers_override() {
+        let map = CarbonMap::new();
+
+        map.insert("default.port", 80);
+        map.insert("override.port", 8080);
+
+        assert_eq!(map.get_first(&["override.port", "default.port"]), Some(8080));
+        assert_eq!(map.get_first(&["missing", "default.port"]), Some(80));
+        assert_eq!(map.get_first(&["missing"]), None);
+    }
+
+    #[test]
+    fn get_or_defaults_mixed() {
+        let map = CarbonMap::new();
+
+        map.insert("a", 1);
+        map.insert("c", 3);
+
+        let out = map.get_or_defaults(&[("a", 0), ("b", 20), ("c", 0), ("d", 40)]);
+
+        assert_eq!(out, vec![1, 20, 3, 40]);
+        assert_eq!(map.get(&"b"), None);
+    }
+
+    #[test]
+    fn export_and_reset_stats() {
+        let map = CarbonMap::new();
+
+        map.insert("a", 2u32);
+        map.insert("b", 4u32);
+        map.insert("c", 9u32);
+
+        let snap = map.export_and_reset();
+
+        assert_eq!(snap.values.len(), 3);
+        assert_eq!(snap.sum, 15.0);
+        assert_eq!(snap.mean, 5.0);
+
+        assert_eq!(map.get(&"a"), Some(0));
+        assert_eq!(map.get(&"b"), Some(0));
+        assert_eq!(map.get(&"c"), Some(0));
+
+        let empty: CarbonMap<&str, u32> = CarbonMap::new();
+
+        assert_eq!(empty.export_and_reset().mean, 0.0);
+    }
+
+    #[test]
+    fn intersects_overlap_and_disjoint() {
+        let a = CarbonMap::new();
+        let b = CarbonMap::new();
+        let c = CarbonMap::new();
+
+        a.insert(1, ());
+        a.insert(2, ());
+        b.insert(2, ());
+        b.insert(3, ());
+        c.insert(4, ());
+
+        assert!(a.intersects(&b));
+        assert!(b.intersects(&a));
+        assert!(!a.intersects(&c));
+        assert!(a.intersects(&a));
+    }
+
+    #[test]
+    fn key_symmetric_difference_partial_overlap() {
+        let a = CarbonMap::new();
+        let b = CarbonMap::new();
+
+        for i in 0..5 {
+            a.insert(i, ());
+        }
+
+        for i in 3..8 {
+            b.insert(i, ());
+        }
+
+        let mut diff = a.key_symmetric_difference(&b);
+        diff.sort();
+
+        assert_eq!(diff, vec![0, 1, 2, 5, 6, 7]);
+        assert!(a.key_symmetric_difference(&a).is_empty());
+    }
+
+    #[test]
+    fn insert_within_budget_bounds_size() {
+        let map = CarbonMap::new();
+
+        let size = |_: &u32, v: &String| v.len();
+
+        for i in 0..20 {
+            map.insert_within_budget(i, "x".repeat(10), 55, size);
+
+            let total: usize = map.values().iter().map(|v| v.len()).sum();
+
+            assert!(total <= 55);
+            assert_eq!(map.get(&i), Some("x".repeat(10)));
+        }
+
+        map.insert_within_budget(99, "y".repeat(100), 55, size);
+
+        assert_eq!(map.get(&99), None);
+    }
+
+    #[test]
+    fn take_if_matching_and_not() {
+        let map = CarbonMap::new();
+
+        map.insert("a", 1);
+        map.insert("b", 2);
+
+        assert_eq!(map.take_if(&"a", |v| *v == 1), Some(1));
+        assert_eq!(map.get(&"a"), None);
+
+        assert_eq!(map.take_if(&"b", |v| *v == 1), None);
+        assert_eq!(map.get(&"b"), Some(2));
+
+        assert_eq!(map.take_if(&"c", |_| true), None);
+    }
+
+    #[test]
+    fn bucket_distribution_roughly_even() {
+        let map = CarbonMap::new();
+
+        for i in 0..16_000 {
+            map.insert(i, ());
+        }
+
+        let dist = map.bucket_distribution(16);
+
+        assert_eq!(dist.len(), 16);
+        assert_eq!(dist.iter().sum::<usize>(), 16_000);
+
+        for n in dist {
+            assert!(n > 500 && n < 1500, "uneven slot: {}", n);
+        }
+
+        assert!(map.bucket_distribution(0).is_empty());
+    }
+
+    #[test]
+    fn compare_and_remove_cases() {
+        let map = CarbonMap::new();
+
+        map.insert("a", 1);
+        map.insert("b", 2);
+
+        assert!(map.compare_and_remove(&"a", &1));
+        assert_eq!(map.get(&"a"), None);
+
+        assert!(!map.compare_and_remove(&"b", &3));
+        assert_eq!(map.get(&"b"), Some(2));
+
+        assert!(!map.compare_and_remove(&"c", &1));
+    }
+
+    #[test]
+    fn get_or_compute_timeout_waiter_times_out() {
+        let map = Arc::new(CarbonMap::new());
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+
+        let m = map.clone();
+        let slow = thread::spawn(move || {
+            m.get_or_compute_timeout("k", Duration::from_secs(5), || {
+                started_tx.send(()).unwrap();
+                release_rx.recv().unwrap();
+                1
+            })
+        });
+
+        // The computation blocks until released, so it is still in flight
+        // for the whole of the waiter's timeout.
+        started_rx.recv().unwrap();
+
+        let res = map.get_or_compute_timeout("k", Duration::from_millis(50), || {
+            panic!("duplicate computation")
+        });
+
+        assert_eq!(res, None);
+
+        release_tx.send(()).unwrap();
+        assert_eq!(slow.join().unwrap(), Some(1));
+
+        let res = map.get_or_compute_timeout("k", Duration::from_millis(50), || 2);
+
+        assert_eq!(res, Some(1));
+    }
+
+    #[test]
+    fn rehash_seed_preserves_contents() {
+        let map = CarbonMap::new();
+
+        for i in 0..1000 {
+            map.insert(i, i * 2);
+        }
+
+        map.rehash_seed();
+
+        for i in 0..1000 {
+            assert_eq!(map.get(&i), Some(i * 2));
+        }
+
+        assert_eq!(map.len(), 1000);
+    }
+
+    #[test]
+    fn get_and_len_consistent() {
+        let map = Arc::new(CarbonMap::new());
+
+        let m = map.clone();
+        let writer = thread::spawn(move || {
+            for i in 0..5000 {
+                m.insert(i, i);
+            }
+        });
+
+        for _ in 0..5000 {
+            match map.get_and_len(&2500) {
+                (Some(v), len) => {
+                    assert_eq!(v, 2500);
+                    assert!(len > 2500);
+                }
+                (None, len) => assert!(len <= 2500),
+            }
+        }
+
+        writer.join().unwrap();
+
+        assert_eq!(map.get_and_len(&2500), (Some(2500), 5000));
+    }
+
+    #[test]
+    fn lock_keys_transfer() {
+        let map = CarbonMap::new();
+
+        map.insert("alice", 100);
+        map.insert("bob", 50);
+        map.insert("carol", 7);
+
+        let moved = map.lock_keys(&["alice", "bob"], |m| {
+            assert_eq!(m.len(), 2);
+
+            *m.get_mut("alice").unwrap() -= 30;
+            *m.get_mut("bob").unwrap() += 30;
+            30
+        });
+
+        assert_eq!(moved, 30);
+        assert_eq!(map.get(&"alice"), Some(70));
+        assert_eq!(map.get(&"bob"), Some(80));
+        assert_eq!(map.get(&"carol"), Some(7));
+    }
+
+    #[test]
+    fn lock_keys_restores_on_panic() {
+        let map = CarbonMap::new();
+
+        map.insert("alice", 100);
+        map.insert("bob", 50);
+
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            map.lock_keys(&["alice", "bob"], |m| {
+                *m.get_mut("alice").unwrap() -= 30;
+                panic!("transfer failed");
+            })
+        }));
+
+        assert!(res.is_err());
+        assert_eq!(map.get(&"alice"), Some(70));
+        assert_eq!(map.get(&"bob"), Some(50));
+        assert_eq!(map.len(), 2);
+    }
+
+    #[test]
+    fn remove_in_range_inclusive() {
+        let map = CarbonMap::new();
+
+        for i in 0..10 {
+            map.insert(i, i);
+        }
+
+        assert_eq!(map.remove_in_range(3, 6), 4);
+
+        for i in 0..10 {
+            assert_eq!(map.get(&i).is_some(), !(3..=6).contains(&i));
+        }
+    }
+
+    #[test]
+    fn collect_where_even_values() {
+        let map = CarbonMap::new();
+
+        for i in 0..10 {
+            map.insert(i, i);
+        }
+
+        let mut evens = map.collect_where(|_, v| v % 2 == 0);
+        evens.sort();
+
+        assert_eq!(evens, vec![(0, 0), (2, 2), (4, 4), (6, 6), (8, 8)]);
+        assert_eq!(map.get(&1), Some(1));
+        assert_eq!(map.len(), 10);
+    }
+
+    #[test]
+    fn value_frequencies_counts() {
+        let map = CarbonMap::new();
+
+        map.insert("a", "red");
+        map.insert("b", "blue");
+        map.insert("c", "red");
+        map.insert("d", "red");
+
+        let freq = map.value_frequencies();
+
+        assert_eq!(freq.len(), 2);
+        assert_eq!(freq[&"red"], 3);
+        assert_eq!(freq[&"blue"], 1);
+    }
+
+    #[test]
+    fn audited_keeps_last_n() {
+        let map = AuditedCarbonMap::new(3);
+
+        for i in 0..6 {
+            map.insert("k", i);
+        }
+
+        assert_eq!(map.get(&"k"), Some(5));
+        assert_eq!(map.history(&"k"), Some(vec![2, 3, 4]));
+
+        assert!(map.update(&"k", |v| *v *= 10));
+
+        assert_eq!(map.get(&"k"), Some(50));
+        assert_eq!(map.history(&"k"), Some(vec![3, 4, 5]));
+
+        assert!(!map.update(&"x", |v| *v += 1));
+        assert_eq!(map.history(&"x"), None);
+    }
+
+    #[test]
+    fn join_get_consistent() {
+        let a = Arc::new(CarbonMap::new());
+        let b = Arc::new(CarbonMap::new());
+
+        a.insert("k", 0u64);
+        b.insert("k", "0".to_string());
+
+        let (wa, wb) = (a.clone(), b.clone());
+        let writer = thread::spawn(move || {
+            for i in 1..5000u64 {
+                wa.insert("k", i);
+                wb.insert("k", i.to_string());
+            }
+        });
+
+        for _ in 0..5000 {
+            let (va, vb) = join_get(&a, &b, &"k");
+            let va = va.unwrap();
+            let vb: u64 = vb.unwrap().parse().unwrap();
+
+            assert!(vb <= va && va - vb <= 1);
+        }
+
+        writer.join().unwrap();
+
+        assert_eq!(join_get(&a, &b, &"missing"), (None, None));
+    }
+
+    #[test]
+    fn join_get_same_map() {
+        let map = Arc::new(CarbonMap::with_shard_count(1));
+
+        map.insert("k", 0u64);
+
+        let m = map.clone();
+        let writer = thread::spawn(move || {
+            for i in 1..5000u64 {
+                m.insert("k", i);
+            }
+        });
+
+        for _ in 0..5000 {
+            let (va, vb) = join_get(&map, &map, &"k");
+            assert_eq!(va, vb);
+        }
+
+        writer.join().unwrap();
+
+        assert_eq!(join_get(&map, &map, &"k"), (Some(4999), Some(4999)));
+    }
+
+    // Fair unlocking only changes who wins a race for a just-released lock,
+    // which a test cannot pin down without hooks inside `get`/`insert`, so
+    // this only covers the unlock_fair paths.
+    #[test]
+    fn fair_locking_basic_ops() {
+        let map = CarbonMap::with_fair_locking(true);
+
+        for i in 0..100 {
+            map.insert(i, i);
+        }
+
+        assert_eq!(map.get(&42), Some(42));
+        assert_eq!(map.remove(&42), Some(42));
+        assert_eq!(map.get(&42), None);
+        assert_eq!(map.len(), 99);
+    }
+
+    #[test]
+    fn for_each_yielding_visits_all() {
+        let map = CarbonMap::new();
+
+        for i in 0..100 {
+            map.insert(i, i);
+        }
+
+        let mut seen = vec![];
+
+        map.for_each_yielding(8, |k, v| {
+            assert_eq!(k, v);
+            seen.push(*k);
+        });
+
+        seen.sort();
+
+        assert_eq!(seen, (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn pop_min_ascending() {
+        let map = CarbonMap::new();
+
+        for (k, v) in [("a", 5), ("b", 1), ("c", 9), ("d", 3)] {
+            map.insert(k, v);
+        }
+
+        let mut popped = vec![];
+
+        while let Some((_, v)) = map.pop_min() {
+            popped.push(v);
+        }
+
+        assert_eq!(popped, vec![1, 3, 5, 9]);
+    }
+
+    #[test]
+    fn pop_max_descending() {
+        let map = CarbonMap::new();
+
+        for (k, v) in [("a", 5), ("b", 1), ("c", 9), ("d", 3)] {
+            map.insert(k, v);
+        }
+
+        assert_eq!(map.pop_max(), Some(("c", 9)));
+        assert_eq!(map.pop_max(), Some(("a", 5)));
+        assert_eq!(map.pop_max(), Some(("d", 3)));
+        assert_eq!(map.pop_max(), Some(("b", 1)));
+        assert_eq!(map.pop_max(), None);
+    }
+
+    #[test]
+    fn count_keys_prefix() {
+        let map = CarbonMap::new();
+
+        map.insert("user:1", 1);
+        map.insert("user:2", 2);
+        map.insert("session:1", 3);
+
+        assert_eq!(map.count_keys(|k| k.starts_with("user:")), 2);
+        assert_eq!(map.count_keys(|k| k.starts_with("none:")), 0);
+    }
+
+    #[test]
+    fn rebuild_drops_and_rekeys() {
+        let map = CarbonMap::new();
+
+        for i in 0..6 {
+            map.insert(i, i * 10);
+        }
+
+        map.rebuild(|k, v| {
+            if k % 2 == 0 {
+                Some((k + 100, v))
+            } else {
+                None
+            }
+        });
+
+        assert_eq!(map.len(), 3);
+        assert_eq!(map.get(&100), Some(0));
+        assert_eq!(map.get(&102), Some(20));
+        assert_eq!(map.get(&104), Some(40));
+        assert_eq!(map.get(&0), None);
+        assert_eq!(map.get(&1), None);
+    }
+
+    #[test]
+    fn get_or_derive_sum() {
+        let map = CarbonMap::new();
+
+        map.insert("a", 1);
+        map.insert("b", 2);
+        map.insert("c", 3);
+
+        let total = map.get_or_derive("total", |m| m.values().sum());
+
+        assert_eq!(total, 6);
+
+        map.insert("d", 4);
+
+        assert_eq!(map.get_or_derive("total", |m| m.values().sum()), 6);
+    }
+
+    #[test]
+    fn remap_renames_and_doubles() {
+        let map = CarbonMap::new();
+
+        map.insert("old", 21);
+        map.insert("new", 1);
+
+        assert!(map.remap(&"old", "new", |v| v * 2));
+        assert_eq!(map.get(&"old"), None);
+        assert_eq!(map.get(&"new"), Some(42));
+
+        assert!(!map.remap(&"missing", "x", |v| v));
+        assert_eq!(map.get(&"x"), None);
+    }
+
+    #[test]
+    fn reader_count_tracks_guards() {
+        let map = CarbonMap::new();
+
+        map.insert(1, 1);
+
+        assert_eq!(map.reader_count(), 0);
+
+        let g1 = map.read_shard(&1);
+        let g2 = map.read_shard(&2);
+
+        assert_eq!(map.reader_count(), 2);
+        assert_eq!(g1.get(&1), Some(&1));
+
+        drop(g1);
+        drop(g2);
+
+        assert_eq!(map.reader_count(), 0);
+    }
+
+    #[test]
+    fn increment_many_accumulates() {
+        let map = CarbonMap::new();
+
+        map.insert("a", 10);
+
+        map.increment_many(vec![("a", 1), ("b", 2), ("a", 3), ("c", 4), ("b", 5)]);
+
+        assert_eq!(map.get(&"a"), Some(14));
+        assert_eq!(map.get(&"b"), Some(7));
+        assert_eq!(map.get(&"c"), Some(4));
+    }
+
+    #[test]
+    fn map_or_else_present_and_absent() {
+        let map = CarbonMap::new();
+
+        map.insert("a", "hello".to_string());
+
+        assert_eq!(map.map_or_else(&"a", |v| v.len(), || 0), 5);
+
+        let absent = map.map_or_else(&"b", |v| v.len(), || {
+            assert_eq!(map.reader_count(), 0);
+            usize::MAX
+        });
+
+        assert_eq!(absent, usize::MAX);
+    }
+
+    #[test]
+    fn retain_nonempty_vecs() {
+        let map = CarbonMap::new();
+
+        map.insert("a", vec![1]);
+        map.insert("b", vec![]);
+        map.insert("c", vec![2, 3]);
+        map.insert("d", vec![]);
+
+        map.retain_nonempty(|v| v.is_empty());
+
+        assert_eq!(map.get(&"a"), Some(vec![1]));
+        assert_eq!(map.get(&"b"), None);
+        assert_eq!(map.get(&"c"), Some(vec![2, 3]));
+        assert_eq!(map.get(&"d"), None);
+    }
+
+    #[test]
+    fn from_columns_zips() {
+        let map = CarbonMap::from_columns(&["a", "b", "c"], &[1, 2, 3]);
+
+        assert_eq!(map.get(&"a"), Some(1));
+        assert_eq!(map.get(&"b"), Some(2));
+        assert_eq!(map.get(&"c"), Some(3));
+    }
+
+    #[test]
+    #[should_panic(expected = "length mismatch")]
+    fn from_columns_mismatch_panics() {
+        let _ = CarbonMap::from_columns(&["a", "b"], &[1]);
+    }
+
+    #[test]
+    fn drain_into_channel_hands_off() {
+        let map = CarbonMap::new();
+
+        for i in 0..10 {
+            map.insert(i, i * 2);
+        }
+
+        let (tx, rx) = mpsc::channel();
+
+        assert_eq!(map.drain_into_channel(&tx), 10);
+        assert_eq!(map.get(&0), None);
+
+        drop(tx);
+
+        let mut got: Vec<_> = rx.iter().collect();
+        got.sort();
+
+        assert_eq!(got, (0..10).map(|i| (i, i * 2)).collect::<Vec<_>>());
+
+        map.insert(1, 1);
+
+        let (tx, rx) = mpsc::channel();
+        drop(rx);
+
+        assert_eq!(map.drain_into_channel(&tx), 0);
+        assert_eq!(map.get(&1), Some(1));
+    }
+
+    #[test]
+    fn all_and_any() {
+        let map = CarbonMap::new();
+
+        assert!(map.all(|_, _| false));
+        assert!(!map.any(|_, _| true));
+
+        for i in 0..5 {
+            map.insert(i, i * 2);
+        }
+
+        assert!(map.all(|_, v| v % 2 == 0));
+        assert!(map.any(|k, _| *k == 3));
+
+        map.insert(9, 7);
+
+        assert!(!map.all(|_, v| v % 2 == 0));
+        assert!(!map.any(|_, v| *v > 100));
+    }
+
+    #[test]
+    fn content_hash_order_independent() {
+        let a = CarbonMap::new();
+        let b = CarbonMap::new();
+
+        for i in 0..100 {
+            a.insert(i, i * 3);
+        }
+
+        for i in (0..100).rev() {
+            b.insert(i, i * 3);
+        }
+
+        assert_eq!(a.content_hash(), b.content_hash());
+
+        b.insert(50, 0);
+
+        assert_ne!(a.content_hash(), b.content_hash());
+    }
+
+    #[test]
+    fn bulk_load_many() {
+        let map = CarbonMap::new();
+
+        map.bulk_load(10_000, (0..10_000).map(|i| (i, i)));
+
+        assert_eq!(map.len(), 10_000);
+        assert_eq!(map.get(&0), Some(0));
+        assert_eq!(map.get(&9_999), Some(9_999));
+
+        map.bulk_load(0, vec![(20_000, 1)]);
+
+        assert_eq!(map.get(&20_000), Some(1));
+    }
+
+    #[test]
+    fn update_average_samples() {
+        let map = CarbonMap::new();
+
+        for s in [2.0, 4.0, 9.0] {
+            map.update_average("latency", s);
+        }
+
+        assert_eq!(map.average(&"latency"), Some(5.0));
+        assert_eq!(map.get(&"latency"), Some((3, 15.0)));
+        assert_eq!(map.average(&"missing"), None);
+    }
+
+    #[test]
+    fn truncate_top_keeps_highest() {
+        let map = CarbonMap::new();
+
+        for (k, v) in [("a", 10), ("b", 50), ("c", 30), ("d", 40), ("e", 20)] {
+            map.insert(k, v);
+        }
+
+        assert_eq!(map.truncate_top(3), 2);
+
+        assert_eq!(map.get(&"b"), Some(50));
+        assert_eq!(map.get(&"d"), Some(40));
+        assert_eq!(map.get(&"c"), Some(30));
+        assert_eq!(map.get(&"e"), None);
+        assert_eq!(map.get(&"a"), None);
+
+        assert_eq!(map.truncate_top(5), 0);
+    }
+
+    #[test]
+    fn increment_snapshot_returns_new_values() {
+        let map = CarbonMap::new();
+
+        map.insert("a", 5u32);
+
+        let snap = map.increment_snapshot(&["a", "b", "a"]);
+
+        assert_eq!(snap, vec![6, 1, 7]);
+        assert_eq!(map.get(&"a"), Some(7));
+        assert_eq!(map.get(&"b"), Some(1));
+    }
+
+    #[test]
+    fn len_and_is_empty() {
+        let map = CarbonMap::new();
+
+        assert_eq!(map.len(), 0);
+        assert!(map.is_empty());
+
+        for i in 0..1000 {
+            map.insert(i, i);
+        }
+
+        assert_eq!(map.len(), 1000);
+        assert!(!map.is_empty());
+    }
+
+    #[test]
+    fn drain_prefix_namespace() {
+        let map = CarbonMap::new();
+
+        map.insert("user:1", 1);
+        map.insert("user:2", 2);
+        map.insert("session:1", 3);
+        map.insert("users", 4);
+
+        let mut drained = map.drain_prefix("user:");
+        drained.sort();
+
+        assert_eq!(drained, vec![("user:1", 1), ("user:2", 2)]);
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get(&"session:1"), Some(3));
+        assert_eq!(map.get(&"users"), Some(4));
+    }
+
+    #[test]
+    fn clear_empties() {
+        let map = CarbonMap::new();
+
+        for i in 0..10 {
+            map.insert(i, i);
+        }
+
+        map.clear();
+
+        assert_eq!(map.len(), 0);
+
+        for i in 0..10 {
+            assert_eq!(map.get(&i), None);
+        }
+    }
+
+    #[test]
+    fn lww_insert_by_timestamp() {
+        let map = CarbonMap::new();
+
+        assert!(map.lww_insert("k", "first", 10));
+        assert!(!map.lww_insert("k", "stale", 5));
+        assert!(!map.lww_insert("k", "tie", 10));
+        assert_eq!(map.get(&"k"), Some(("first", 10)));
+
+        assert!(map.lww_insert("k", "newer", 11));
+        assert_eq!(map.get(&"k"), Some(("newer", 11)));
+    }
+
+    #[test]
+    fn contains_key_without_clone() {
+        struct Buffer;
+
+        let map = CarbonMap::new();
+
+        map.insert("a", Buffer);
+
+        assert!(map.contains_key(&"a"));
+        assert!(!map.contains_key(&"b"));
+    }
+
+    #[test]
+    fn sample_counts_and_membership() {
+        let map = CarbonMap::new();
+
+        for i in 0..100 {
+            map.insert(i, i * 2);
+        }
+
+        let picked = map.sample(10);
+
+        assert_eq!(picked.len(), 10);
+
+        let mut keys: Vec<_> = picked.iter().map(|(k, _)| *k).collect();
+        keys.sort();
+        keys.dedup();
+
+        assert_eq!(keys.len(), 10);
+
+        for (k, v) in picked {
+            assert_eq!(map.get(&k), Some(v));
+        }
+
+        assert_eq!(map.sample(500).len(), 100);
+        assert!(map.sample(0).is_empty());
+    }
+
+    #[test]
+    fn clear_and_shrink_releases() {
+        let map = CarbonMap::new();
+
+        for i in 0..10_000 {
+            map.insert(i, i);
+        }
+
+        let before = capacity(&map);
+
+        map.clear_and_shrink();
+
+        assert!(map.is_empty());
+        assert!(capacity(&map) < before);
+    }
+
+    #[test]
+    fn custom_hasher() {
+        use std::hash::BuildHasherDefault;
+
+        let map: CarbonMap<&str, i32, BuildHasherDefault<DefaultHasher>> =
+            CarbonMap::with_hasher(BuildHasherDefault::default());
+
+        map.insert("a", 1);
+        let _ = map.entry("b").or_insert(2);
+
+        assert_eq!(map.get(&"a"), Some(1));
+        assert_eq!(map.get(&"b"), Some(2));
+        assert_eq!(map.len(), 2);
+    }
+
+    #[test]
+    fn apply_patch_removes_then_inserts() {
+        let map = CarbonMap::new();
+
+        map.insert("a", 1);
+        map.insert("b", 2);
+        map.insert("c", 3);
+
+        map.apply_patch(vec![("c", 30), ("d", 4)], vec!["a", "c"]);
+
+        assert_eq!(map.get(&"a"), None);
+        assert_eq!(map.get(&"b"), Some(2));
+        assert_eq!(map.get(&"c"), Some(30));
+        assert_eq!(map.get(&"d"), Some(4));
+        assert_eq!(map.len(), 3);
+    }
+
+    #[test]
+    fn update_in_place() {
+        let map = CarbonMap::new();
+
+        map.insert("hits", 1);
+
+        assert_eq!(map.update(&"hits", |v| { *v += 1; *v }), Some(2));
+        assert_eq!(map.get(&"hits"), Some(2));
+
+        assert_eq!(map.update(&"misses", |v| *v += 1), None);
+        assert!(!map.contains_key(&"misses"));
+    }
+
+    #[test]
+    fn retain_even_keys() {
+        let map = CarbonMap::new();
+
+        for i in 0..100 {
+            map.insert(i, i);
+        }
+
+        map.retain(|k, _| k % 2 == 0);
+
+        assert_eq!(map.len(), 50);
+
+        for i in 0..100 {
+            assert_eq!(map.contains_key(&i), i % 2 == 0);
+        }
+    }
+
+    #[test]
+    fn total_value_size_strings() {
+        let map = CarbonMap::new();
+
+        map.insert(1, "abc".to_string());
+        map.insert(2, "de".to_string());
+        map.insert(3, String::new());
+
+        assert_eq!(map.total_value_size(|v| v.len()), 5);
+    }
+
+    #[test]
+    fn insert_or_modify_both_branches() {
+        let map = CarbonMap::new();
+
+        map.insert_or_modify("k", vec![1], |v| v.push(99));
+
+        assert_eq!(map.get(&"k"), Some(vec![1]));
+
+        map.insert_or_modify("k", vec![1], |v| v.push(2));
+
+        assert_eq!(map.get(&"k"), Some(vec![1, 2]));
+    }
+
+    #[test]
+    fn default_is_empty() {
+        #[derive(Default)]
+        struct Registry {
+            map: CarbonMap<String, u32>,
+        }
+
+        let reg = Registry::default();
+
+        assert!(reg.map.is_empty());
+
+        reg.map.insert("a".to_string(), 1);
+
+        assert_eq!(reg.map.get(&"a".to_string()), Some(1));
+    }
+
+    #[test]
+    fn from_iter_and_extend() {
+        let mut map: CarbonMap<_, _> = (0..10).map(|i| (i, i * 2)).collect();
+
+        assert_eq!(map.len(), 10);
+        assert_eq!(map.get(&3), Some(6));
+
+        map.extend((10..20).map(|i| (i, i * 2)));
+
+        assert_eq!(map.len(), 20);
+
+        let shared = Arc::new(map);
+        let mut handle = &*shared;
+        handle.extend(vec![(0, 100)]);
+
+        assert_eq!(shared.get(&0), Some(100));
+        assert_eq!(shared.len(), 20);
+    }
+
+    #[test]
+    fn into_snapshot_iter_combinators() {
+        let map = CarbonMap::new();
+
+        for i in 0..10 {
+            map.insert(i, i);
+        }
+
+        let mut out: Vec<i32> = map
+            .into_snapshot_iter()
+            .filter(|(k, _)| k % 3 == 0)
+            .map(|(_, v)| v * 10)
+            .collect();
+        out.sort();
+
+        assert_eq!(out, vec![0, 30, 60, 90]);
+        assert_eq!(map.reader_count(), 0);
+    }
+
+    #[test]
+    fn with_capacity_preallocates() {
+        let map = CarbonMap::with_capacity(1000);
+
+        assert!(capacity(&map) >= 1000);
+        assert!(map.is_empty());
+
+        map.insert(1, 1);
+
+        assert_eq!(map.get(&1), Some(1));
+    }
+
+    #[test]
+    fn get_or_insert_with_stats_hit_miss() {
+        let map = CarbonMap::new();
+
+        assert_eq!(map.get_or_insert_with_stats("k", || 7), (7, true));
+        assert_eq!(map.get_or_insert_with_stats("k", || 8), (7, false));
+        assert_eq!(map.get_or_insert_with_stats("k", || 9), (7, false));
+    }
+
+    #[test]
+    fn release_many_collects_zeroed() {
+        let map = CarbonMap::new();
+
+        map.insert("a", 1u32);
+        map.insert("b", 2u32);
+        map.insert("c", 1u32);
+
+        let released = map.release_many(&["a", "b", "c", "missing"]);
+
+        assert_eq!(released, vec!["a", "c"]);
+        assert!(!map.contains_key(&"a"));
+        assert!(!map.contains_key(&"c"));
+        assert_eq!(map.get(&"b"), Some(1));
+    }
+
+    #[test]
+    fn release_many_zero_count() {
+        let map = CarbonMap::new();
+
+        map.insert("a", 0u32);
+
+        assert_eq!(map.release_many(&["a"]), vec!["a"]);
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn iter_keys_values_snapshots() {
+        let map = CarbonMap::new();
+
+        for i in 0..5 {
+            map.insert(i, i * 10);
+        }
+
+        let mut entries = map.iter();
+        let mut keys = map.keys();
+        let mut vals = map.values();
+
+        map.insert(99, 0);
+
+        entries.sort();
+        keys.sort();
+        vals.sort();
+
+        assert_eq!(entries, vec![(0, 0), (1, 10), (2, 20), (3, 30), (4, 40)]);
+        assert_eq!(keys, vec![0, 1, 2, 3, 4]);
+        assert_eq!(vals, vec![0, 10, 20, 30, 40]);
+    }
+
+    #[test]
+    fn insert_returns_previous() {
+        let map = CarbonMap::new();
+
+        assert_eq!(map.insert("a", 1), None);
+        assert_eq!(map.insert("a", 2), Some(1));
+        assert_eq!(map.get(&"a"), Some(2));
+    }
+
+    #[test]
+    fn snapshot_into_reuses_buffer() {
+        let map = CarbonMap::new();
+
+        for i in 0..50 {
+            map.insert(i, i);
+        }
+
+        let mut buf = Vec::new();
+
+        map.snapshot_into(&mut buf);
+        buf.sort();
+
+        assert_eq!(buf, (0..50).map(|i| (i, i)).collect::<Vec<_>>());
+
+        let cap = buf.capacity();
+
+        map.retain(|k, _| *k < 3);
+        map.snapshot_into(&mut buf);
+        buf.sort();
+
+        assert_eq!(buf, vec![(0, 0), (1, 1), (2, 2)]);
+        assert_eq!(buf.capacity(), cap);
+    }
+
+    #[test]
+    fn concurrent_test_and_set() {
+        let map = Arc::new(CarbonMap::new());
+
+        let mut handles = vec![];
+
+        for _ in 0..16 {
+            let m = map.clone();
+
+            handles.push(thread::spawn(move || m.test_and_set("init")));
+        }
+
+        let firsts = handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .filter(|&prior| !prior)
+            .count();
+
+        assert_eq!(firsts, 1);
+        assert_eq!(map.get(&"init"), Some(true));
+    }
+
+    #[test]
+    fn try_entry_avoids_self_deadlock() {
+        let map = CarbonMap::new();
+
+        let held = map.entry("a").or_insert(1);
+
+        // `map.entry("a")` here would block forever on this thread.
+        assert!(map.try_entry("a").is_none());
+
+        drop(held);
+
+        let v = map.try_entry("b").unwrap().or_insert(2);
+
+        assert_eq!(*v, 2);
+    }
+
+    #[test]
+    fn get_or_insert_clones() {
+        let map = CarbonMap::new();
+
+        assert_eq!(map.get_or_insert("a", 1), 1);
+        assert_eq!(map.get_or_insert("a", 2), 1);
+
+        assert_eq!(map.get_or_insert_with("b", || 3), 3);
+        assert_eq!(map.get_or_insert_with("b", || panic!("already present")), 3);
+
+        // no guard is held, so further calls don't deadlock
+        map.insert("a", 10);
+
+        assert_eq!(map.get(&"a"), Some(10));
+    }
+
+    #[test]
+    fn update_min_max_track_extremes() {
+        let lo = CarbonMap::new();
+        let hi = CarbonMap::new();
+
+        for s in [5, 3, 8, 1, 9, 4] {
+            lo.update_min("k", s);
+            hi.update_max("k", s);
+        }
+
+        assert_eq!(lo.get(&"k"), Some(1));
+        assert_eq!(hi.get(&"k"), Some(9));
+    }
+
+    #[test]
+    fn project_into_records() {
+        #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+        struct Row {
+            id: u32,
+            name_len: usize,
+        }
+
+        let map = CarbonMap::new();
+
+        map.insert(1, "alice".to_string());
+        map.insert(2, "bob".to_string());
+
+        let mut rows = map.project(|k, v| Row { id: *k, name_len: v.len() });
+        rows.sort();
+
+        assert_eq!(
+            rows,
+            vec![Row { id: 1, name_len: 5 }, Row { id: 2, name_len: 3 }]
+        );
+    }
+
+    #[test]
+    fn clone_is_independent() {
+        let a = CarbonMap::new();
+
+        a.insert("x", 1);
+
+        let b = a.clone();
+
+        a.insert("x", 2);
+        b.insert("y", 3);
+
+        assert_eq!(a.get(&"x"), Some(2));
+        assert_eq!(b.get(&"x"), Some(1));
+        assert!(!a.contains_key(&"y"));
+    }
+
+    #[test]
+    fn entry_default_mut_autocreates() {
+        let map: CarbonMap<&str, Vec<i32>> = CarbonMap::new();
+
+        map.entry_default_mut("list").push(1);
+        map.entry_default_mut("list").push(2);
+
+        assert_eq!(map.get(&"list"), Some(vec![1, 2]));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_json_roundtrip() {
+        let map = CarbonMap::new();
+
+        map.insert("a".to_string(), 1);
+        map.insert("b".to_string(), 2);
+
+        let json = serde_json::to_string(&map).unwrap();
+        let back: CarbonMap<String, i32> = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(back.len(), 2);
+        assert_eq!(back.get(&"a".to_string()), Some(1));
+        assert_eq!(back.get(&"b".to_string()), Some(2));
+    }
+
+    #[test]
+    fn pop_min_n_batch() {
+        let map = CarbonMap::new();
+
+        for (k, v) in [("a", 50), ("b", 10), ("c", 40), ("d", 20), ("e", 30)] {
+            map.insert(k, v);
+        }
+
+        assert_eq!(map.pop_min_n(3), vec![("b", 10), ("d", 20), ("e", 30)]);
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.pop_min_n(10), vec![("c", 40), ("a", 50)]);
+        assert!(map.pop_min_n(1).is_empty());
+    }
+
+    #[test]
+    fn get_many_preserves_order() {
+        let map = CarbonMap::new();
+
+        map.insert("a", 1);
+        map.insert("c", 3);
+
+        assert_eq!(map.get_many(&["c", "b", "a"]), vec![Some(3), None, Some(1)]);
+    }
+
+    #[test]
+    fn validate_reports_violations() {
+        let map = CarbonMap::new();
+
+        for i in 0..10 {
+            map.insert(i, i);
+        }
+
+        map.insert(3, -3);
+        map.insert(7, -7);
+
+        let mut bad = map.validate(|_, v| *v >= 0);
+        bad.sort();
+
+        assert_eq!(bad, vec![3, 7]);
+    }
+
+    #[test]
+    fn compute_insert_update_delete() {
+        let map = CarbonMap::new();
+
+        map.compute("k", |cur| {
+            assert_eq!(cur, None);
+            Some(1)
+        });
+
+        assert_eq!(map.get(&"k"), Some(1));
+
+        map.compute("k", |cur| cur.map(|v| v + 1));
+
+        assert_eq!(map.get(&"k"), Some(2));
+
+        map.compute("k", |_| None);
+
+        assert!(!map.contains_key(&"k"));
+    }
+
+    #[test]
+    fn compute_if_present_skips_missing() {
+        let map = CarbonMap::new();
+
+        assert!(!map.compute_if_present(&"k", |_| panic!("absent")));
+
+        map.insert("k", 5);
+
+        assert!(map.compute_if_present(&"k", |v| Some(v * 2)));
+        assert_eq!(map.get(&"k"), Some(10));
+
+        assert!(map.compute_if_present(&"k", |_| None));
+        assert!(!map.contains_key(&"k"));
+    }
+
+    #[test]
+    fn remove_if_predicate() {
+        let map = CarbonMap::new();
+
+        map.insert("expired", 0);
+        map.insert("live", 10);
+
+        assert_eq!(map.remove_if(&"expired", |ttl| *ttl == 0), Some(0));
+        assert_eq!(map.remove_if(&"live", |ttl| *ttl == 0), None);
+        assert_eq!(map.remove_if(&"missing", |_| true), None);
+
+        assert!(!map.contains_key(&"expired"));
+        assert_eq!(map.get(&"live"), Some(10));
+    }
+
+    #[test]
+    fn transform_where_doubles_even_keys() {
+        let map = CarbonMap::new();
+
+        for i in 0..6 {
+            map.insert(i, i + 1);
+        }
+
+        assert_eq!(map.transform_where(|k, _| k % 2 == 0, |v| *v *= 2), 3);
+
+        assert_eq!(map.get(&0), Some(2));
+        assert_eq!(map.get(&1), Some(2));
+        assert_eq!(map.get(&2), Some(6));
+        assert_eq!(map.get(&3), Some(4));
+        assert_eq!(map.get(&4), Some(10));
+        assert_eq!(map.get(&5), Some(6));
+    }
+
+    #[test]
+    fn sorted_by_value_tie_break() {
+        let map = CarbonMap::new();
+
+        map.insert("carol", 2);
+        map.insert("bob", 1);
+        map.insert("alice", 2);
+        map.insert("dave", 1);
+
+        assert_eq!(
+            map.sorted_by_value(),
+            vec![("bob", 1), ("dave", 1), ("alice", 2), ("carol", 2)]
+        );
+    }
+
+    #[test]
+    fn get_ref_without_clone() {
+        struct Big {
+            id: u32,
+            _payload: [u8; 256],
+        }
+
+        let map = CarbonMap::new();
+
+        map.insert("a", Big { id: 7, _payload: [0; 256] });
+
+        assert_eq!(map.get_ref(&"a", |b| b.id), Some(7));
+        assert_eq!(map.get_ref(&"b", |b| b.id), None);
+    }
+
+    #[test]
+    fn insert_keyed_content_addressed() {
+        let map = CarbonMap::new();
+
+        let key = map.insert_keyed("hello".to_string(), |v| v.len());
+
+        assert_eq!(key, 5);
+        assert_eq!(map.get(&5), Some("hello".to_string()));
+    }
+
+    #[test]
+    fn occupied_entry_insert_and_remove() {
+        let map = CarbonMap::new();
+
+        map.insert("a", 1);
+        map.insert("b", 2);
+
+        if let Entry::Occupied(mut e) = map.entry("a") {
+            assert_eq!(e.insert(10), 1);
+            assert_eq!(*e.get(), 10);
+        } else {
+            panic!("expected occupied");
+        }
+
+        assert_eq!(map.get(&"a"), Some(10));
+
+        if let Entry::Occupied(e) = map.entry("b") {
+            assert_eq!(e.remove(), 2);
+        } else {
+            panic!("expected occupied");
+        }
+
+        assert!(!map.contains_key(&"b"));
+    }
+
+    #[test]
+    fn dedup_arcs_shares_equal_values() {
+        let map = CarbonMap::new();
+
+        map.insert(1, Arc::new("red".to_string()));
+        map.insert(2, Arc::new("red".to_string()));
+        map.insert(3, Arc::new("blue".to_string()));
+        map.insert(4, Arc::new("red".to_string()));
+
+        assert_eq!(map.dedup_arcs(), 2);
+
+        let (a, b, d) = (map.get(&1).unwrap(), map.get(&2).unwrap(), map.get(&4).unwrap());
+
+        assert!(Arc::ptr_eq(&a, &b));
+        assert!(Arc::ptr_eq(&a, &d));
+        assert_eq!(*map.get(&3).unwrap(), "blue");
+
+        assert_eq!(map.dedup_arcs(), 0);
+    }
+
+    #[test]
+    fn entry_key_accessors() {
+        let map = CarbonMap::new();
+
+        map.insert("a".to_string(), 1);
+
+        let e = map.entry("a".to_string());
+        assert_eq!(e.key(), "a");
+
+        if let Entry::Occupied(o) = &e {
+            assert_eq!(o.key(), "a");
+        } else {
+            panic!("expected occupied");
+        }
+
+        drop(e);
+
+        let e = map.entry("b".to_string());
+        assert_eq!(e.key(), "b");
+
+        if let Entry::Vacant(v) = &e {
+            assert_eq!(v.key(), "b");
+        } else {
+            panic!("expected vacant");
+        }
+    }
+
+    #[test]
+    fn concurrent_record_first() {
+        let map = Arc::new(CarbonMap::new());
+
+        let mut handles = vec![];
+
+        for t in 0..8 {
+            let m = map.clone();
+
+            handles.push(thread::spawn(move || {
+                (0..100).filter(|i| m.record_first(*i, t)).count()
+            }));
+        }
+
+        let firsts: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+
+        assert_eq!(firsts, 100);
+        assert_eq!(map.len(), 100);
+    }
+
+    #[test]
+    fn vacant_into_key_releases() {
+        let map: CarbonMap<String, i32> = CarbonMap::new();
+
+        let key = match map.entry("expensive".to_string()) {
+            Entry::Vacant(v) => v.into_key(),
+            Entry::Occupied(_) => panic!("expected vacant"),
+        };
+
+        assert_eq!(key, "expensive");
+        assert!(map.is_empty());
+
+        map.insert(key, 1);
+
+        assert_eq!(map.get(&"expensive".to_string()), Some(1));
+    }
+
+    #[test]
+    fn reconcile_plan_lists() {
+        let map = CarbonMap::new();
+
+        map.insert("keep", 1);
+        map.insert("change", 2);
+        map.insert("drop", 3);
+
+        let desired = HashMap::from([("keep", 1), ("change", 20), ("add", 4)]);
+
+        let (ins, upd, del) = map.reconcile_plan(&desired);
+
+        assert_eq!(ins, vec![("add", 4)]);
+        assert_eq!(upd, vec![("change", 20)]);
+        assert_eq!(del, vec!["drop"]);
+        assert_eq!(map.len(), 3);
+    }
+
+    #[test]
+    fn reconcile_converges() {
+        let map = CarbonMap::new();
+
+        map.insert("keep", 1);
+        map.insert("change", 2);
+        map.insert("drop", 3);
+
+        let desired = HashMap::from([("keep", 1), ("change", 20), ("add", 4)]);
+
+        let stats = map.reconcile(desired.clone());
+
+        assert_eq!(
+            stats,
+            ReconcileStats {
+                inserted: 1,
+                updated: 1,
+                deleted: 1,
+            }
+        );
+
+        let mut now = map.iter();
+        now.sort();
+
+        let mut want: Vec<_> = desired.into_iter().collect();
+        want.sort();
+
+        assert_eq!(now, want);
+    }
+
+    #[test]
+    fn entry_ref_borrowed_key() {
+        let map: CarbonMap<String, i32> = CarbonMap::new();
+
+        let _ = map.entry_ref("hits").or_insert(0);
+
+        let _ = map.entry_ref("hits")
+            .and_modify(|v| *v += 1)
+            .or_insert(0);
+
+        assert_eq!(map.get(&"hits".to_string()), Some(1));
+
+        match map.entry_ref("hits") {
+            EntryRef::Occupied(e) => {
+                assert_eq!(e.key(), "hits");
+                assert_eq!(e.remove(), 1);
+            }
+            EntryRef::Vacant(_) => panic!("expected occupied"),
+        }
+
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn shard_count_rounds_up() {
+        assert_eq!(CarbonMap::<i32, i32>::with_shard_count(5).shard_count(), 8);
+        assert_eq!(CarbonMap::<i32, i32>::with_shard_count(0).shard_count(), 1);
+        assert!(CarbonMap::<i32, i32>::new().shard_count().is_power_of_two());
+        assert!(CarbonMap::<i32, i32>::new().shard_count() <= 16);
+
+        let map = CarbonMap::with_shard_count(1);
+
+        for i in 0..100 {
+            map.insert(i, i);
+        }
+
+        assert_eq!(map.len(), 100);
+        assert_eq!(map.remove(&7), Some(7));
+        assert_eq!(*map.entry(7).or_insert(70), 70);
+    }
+
+    #[test]
+    fn shards_lock_independently() {
+        let map = CarbonMap::with_shard_count(16);
+
+        for i in 0..1000 {
+            map.insert(i, i);
+        }
+
+        let used = map.shards.iter().filter(|s| !s.map.read().is_empty()).count();
+        assert!(used > 8);
+
+        let other = (1..1000)
+            .find(|k| map.shard_index(k) != map.shard_index(&0))
+            .unwrap();
+
+        let held = map.entry(0).or_insert(0);
+
+        assert!(map.try_entry(0).is_none());
+        assert!(map.try_entry(other).is_some());
+
+        drop(held);
+
+        let map = Arc::new(map);
+
+        let handles: Vec<_> = (0..4)
+            .map(|t| {
+                let map = Arc::clone(&map);
+                thread::spawn(move || {
+                    for i in 0..1000 {
+                        map.insert(1000 + t * 1000 + i, i);
+                    }
+                })
+            })
+            .collect();
+
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        assert_eq!(map.len(), 5000);
+    }
+
+    #[test]
+    fn reader_count_is_per_shard() {
+        let map: CarbonMap<i32, i32> = CarbonMap::with_shard_count(16);
+
+        let other = (1..1000)
+            .find(|k| map.shard_index(k) != map.shard_index(&0))
+            .unwrap();
+
+        let g1 = map.read_shard(&0);
+        let g2 = map.read_shard(&other);
+
+        assert_eq!(map.shards[map.shard_index(&0)].readers.load(Ordering::Relaxed), 1);
+        assert_eq!(map.shards[map.shard_index(&other)].readers.load(Ordering::Relaxed), 1);
+        assert_eq!(map.reader_count(), 2);
+
+        drop(g1);
+        drop(g2);
+
+        assert_eq!(map.reader_count(), 0);
+    }
+
+    #[test]
+    fn entry_insert_grows_table() {
+        fn assert_sync<T: Sync>() {}
+
+        assert_sync::<OccupiedEntry<'static, u32, u32>>();
+        assert_sync::<OccupiedEntryRef<'static, 'static, String, str, u32>>();
+
+        let map = CarbonMap::with_shard_count(1);
+
+        // Each vacant insert may resize the table while the entry's lock is
+        // held, and the returned guard must still point at the new slot.
+        for i in 0..10_000 {
+            *map.entry(i).or_insert(0) += i;
+            assert_eq!(map.get(&i), Some(i));
+        }
+
+        assert!(capacity(&map) >= 10_000);
+
+        for i in 0..10_000 {
+            map.entry(i).and_modify(|v| *v += 1);
+        }
+
+        match map.entry(5_000) {
+            Entry::Occupied(mut e) => {
+                assert_eq!(e.insert(0), 5_001);
+                *e.get_mut() += 7;
+                assert_eq!(e.remove(), 7);
+            }
+            Entry::Vacant(_) => panic!("expected occupied"),
+        }
+
+        assert_eq!(map.get(&9_999), Some(10_000));
+        assert_eq!(map.get(&5_000), None);
+        assert_eq!(map.len(), 9_999);
+    }
+
+    #[test]
+    fn get_many_skips_unrelated_shards() {
+        let map = Arc::new(CarbonMap::with_shard_count(16));
+
+        for i in 0..100 {
+            map.insert(i, i);
+        }
+
+        let keys: Vec<i32> = (1..100)
+            .filter(|k| map.shard_index(k) != map.shard_index(&0))
+            .take(5)
+            .collect();
+
+        let held = map.entry(0).or_insert(0);
+
+        let (tx, rx) = mpsc::channel();
+        let m = map.clone();
+        let want = keys.clone();
+
+        let reader = thread::spawn(move || {
+            tx.send(m.get_many(&want)).unwrap();
+        });
+
+        let got = rx.recv_timeout(Duration::from_secs(5)).expect("blocked on an unrelated shard");
+
+        assert_eq!(got, keys.iter().map(|k| Some(*k)).collect::<Vec<_>>());
+
+        drop(held);
+        reader.join().unwrap();
     }
 }